@@ -0,0 +1,4347 @@
+//! Forks dependencies, clones them into the workspace and patches the workspace to use them.
+
+use anyhow::anyhow;
+use cargo::{
+    core::{
+        dependency::DepKind,
+        registry::PackageRegistry,
+        resolver::{CliFeatures, HasDevUnits, Resolve},
+        Manifest, PackageId, PackageSet, Source, SourceId, SourceMap, Workspace,
+    },
+    ops::{generate_lockfile, load_pkg_lockfile, resolve_with_previous, resolve_ws},
+    sources::RegistrySource,
+    util::{
+        closest, config::Config, important_paths::find_root_manifest_for_wd, CanonicalUrl, IntoUrl,
+    },
+};
+use clap::{Args, Parser};
+use git2::{
+    build::RepoBuilder, BranchType, Cred, CredentialType, DescribeOptions, Direction, ErrorClass,
+    ErrorCode, FetchOptions, Remote, RemoteCallbacks, Repository, SubmoduleUpdateOptions,
+};
+use octocrab::{params::repos::Reference, Octocrab};
+use semver::{Comparator, Op, Prerelease, Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use termcolor::{Buffer, Color, ColorSpec, WriteColor};
+
+use std::{
+    collections::{HashSet, VecDeque},
+    env, fmt, fs,
+    io::Write,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicU8, Ordering},
+        mpsc,
+    },
+    task::Poll,
+    thread,
+    time::Duration,
+};
+use toml_edit::{Array, Document, InlineTable, Item, Key, Table};
+use webbrowser::open;
+
+pub type Result<T, E = ForkdepError> = std::result::Result<T, E>;
+
+/// The ways forking and unforking dependencies fail.
+#[derive(thiserror::Error, Debug)]
+pub enum ForkdepError {
+    #[error("Could not find use of dependency {dependency}{searched}, {hint}")]
+    NotFound {
+        dependency: String,
+        /// The members searched, for virtual workspaces.
+        searched: String,
+        hint: String,
+    },
+    #[error("{dependency} is already a local path dependency at {}", path.display())]
+    AlreadyLocal { dependency: String, path: PathBuf },
+    #[error(
+        "{dependency} is already a local path dependency at {}, as a member of the workspace",
+        path.display()
+    )]
+    WorkspaceMember { dependency: String, path: PathBuf },
+    #[error("no version of {dependency} matches {req}, available versions: {available}")]
+    NoMatchingVersion {
+        dependency: String,
+        req: VersionReq,
+        available: String,
+    },
+    #[error("found multiple versions of {dependency}: {versions}, choose one with --version")]
+    MultipleVersions {
+        dependency: String,
+        versions: String,
+    },
+    #[error("{dependency} is not a {} of the workspace", kind.description())]
+    WrongKind {
+        dependency: String,
+        kind: DependencyKind,
+    },
+    #[error("{dependency} does not come from the registry {registry}")]
+    NotInRegistry {
+        dependency: String,
+        registry: String,
+    },
+    #[error("{dependency} does not come from {source_name}")]
+    NotFromSource {
+        dependency: String,
+        source_name: String,
+    },
+    #[error("found {dependency} from several sources: {sources}, choose one with --source")]
+    MultipleSources { dependency: String, sources: String },
+    #[error("could not find a repository for {dependency}, tried {tried}")]
+    NoRepository {
+        dependency: String,
+        /// The places the repository was looked for.
+        tried: String,
+    },
+    #[error(
+        "{repository} already exists but is not a fork of {upstream}, rename it to fork {upstream}"
+    )]
+    ForkNameTaken {
+        repository: String,
+        upstream: String,
+    },
+    #[error("could not clone {url}: {cause}")]
+    CloneFailed {
+        url: String,
+        cause: Box<ForkdepError>,
+    },
+    #[error(
+        "{} already exists but is not a {kind}, remove it or pass --force to replace it",
+        path.display()
+    )]
+    PathExists { path: PathBuf, kind: &'static str },
+    #[error("{dependency} has not been forked")]
+    NotForked { dependency: String },
+    #[error("{} does not contain the crate {dependency}", path.display())]
+    MissingCrate { dependency: String, path: PathBuf },
+    #[error("could not ask for {what} as stdin is not a terminal and has run out, {hint}")]
+    NotInteractive {
+        what: &'static str,
+        hint: &'static str,
+    },
+    #[error("no {what} was entered within {} seconds", timeout.as_secs())]
+    PromptTimeout {
+        what: &'static str,
+        timeout: Duration,
+    },
+    #[error(transparent)]
+    Git(#[from] git2::Error),
+    #[error(transparent)]
+    GitHub(#[from] octocrab::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+#[derive(Parser, Debug)]
+#[clap(name = "cargo")]
+#[clap(bin_name = "cargo")]
+pub enum Cargo {
+    Forkdep(Forkdep),
+}
+
+#[derive(clap::Args, Debug)]
+#[clap(author, version, about, long_about = None)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+#[clap(disable_version_flag = true)]
+pub struct Forkdep {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
+    // `--version` selects the version of the dependency, like in `cargo install`
+    /// Print version information
+    #[clap(short = 'V', action = clap::ArgAction::Version)]
+    pub print_version: Option<bool>,
+
+    /// Version of the dependency to fork, when several are in the lockfile
+    #[clap(long = "version", value_parser = parse_version_req)]
+    pub dep_version: Option<VersionReq>,
+
+    /// Only fork the dependency when the workspace uses it as this kind of dependency
+    #[clap(long, value_enum)]
+    pub dep_kind: Option<DependencyKind>,
+
+    /// Registry from `.cargo/config.toml` to fork the dependency from
+    #[clap(long, value_parser)]
+    pub registry: Option<String>,
+
+    /// Source to fork the dependency from when it comes from several: `crates-io`, the name of
+    /// a registry or the url of a source
+    #[clap(long = "source", value_parser, value_name = "SOURCE")]
+    pub dep_source: Option<String>,
+
+    /// Table to write the patch into: `crates-io`, the name of a registry, the url of a source,
+    /// or `auto` to use the source of the dependency
+    #[clap(long, value_parser, value_name = "TABLE", default_value = "auto")]
+    pub patch_table: String,
+
+    /// Dependencies to fork, as `name` or `name@version`
+    #[clap(
+        required_unless_present = "url",
+        value_parser = parse_dependency_spec,
+        value_name = "SPEC"
+    )]
+    pub dependencies: Vec<DependencySpec>,
+
+    /// Fork the repository at this url instead of looking the dependency up in the lockfile,
+    /// for crates that aren't a dependency yet
+    #[clap(
+        long,
+        value_parser,
+        conflicts_with_all = &[
+            "dependencies", "use-path", "print-url", "git-ref-from-lock", "dep-kind", "registry",
+            "dep-version", "dep-source"
+        ]
+    )]
+    pub url: Option<String>,
+
+    /// Name of the crate forked with `--url` [default: the name of the repository]
+    #[clap(long = "as", value_parser, value_name = "NAME", requires = "url")]
+    pub package: Option<String>,
+
+    #[clap(long, value_parser)]
+    pub manifest_path: Option<PathBuf>,
+
+    /// Commit to check out in the fork after cloning
+    #[clap(long, value_parser, group = "git-ref")]
+    pub rev: Option<String>,
+
+    /// Branch to check out in the fork after cloning
+    #[clap(long, value_parser, group = "git-ref")]
+    pub branch: Option<String>,
+
+    /// Tag to check out in the fork after cloning
+    #[clap(long, value_parser, group = "git-ref")]
+    pub tag: Option<String>,
+
+    /// Check out the commit the lockfile pins a git dependency to
+    #[clap(long, group = "git-ref")]
+    pub git_ref_from_lock: bool,
+
+    /// GitHub personal access token to fork with, instead of a stored one
+    #[clap(long, value_parser)]
+    pub token: Option<String>,
+
+    /// Domain or url of a GitHub Enterprise server, detected for domains starting with `github.`
+    #[clap(long, value_parser)]
+    pub host: Option<String>,
+
+    /// Owner of an existing fork, skips the interactive prompt
+    #[clap(long, value_parser)]
+    pub owner: Option<String>,
+
+    /// Seconds to wait for an answer to be entered before giving up
+    #[clap(long, value_parser, value_name = "SECS", global = true)]
+    pub prompt_timeout: Option<u64>,
+
+    /// Print the url of the repository to fork by hand instead of opening it in the browser,
+    /// the default when there is no display or `CI` is set
+    #[clap(long)]
+    pub no_browser: bool,
+
+    /// Print the planned actions without forking, cloning or editing the manifest
+    #[clap(long)]
+    pub dry_run: bool,
+
+    /// Only print the repository url of each dependency, without forking, cloning or editing
+    /// the manifest
+    #[clap(long, conflicts_with = "dry-run")]
+    pub print_url: bool,
+
+    /// Patch the dependency to the crate in this directory instead of forking and cloning it
+    #[clap(
+        long,
+        value_parser,
+        value_name = "DIR",
+        conflicts_with_all = &[
+            "commit", "track", "new-branch", "git-ref", "shallow", "single-branch", "filter",
+            "open-editor"
+        ]
+    )]
+    pub use_path: Option<PathBuf>,
+
+    /// Also patch the other crates of the workspace in the fork that the workspace depends on,
+    /// all pointing into the one clone
+    #[clap(long, conflicts_with = "use-path")]
+    pub siblings: bool,
+
+    /// Clone the original repository instead of forking it, for experiments that won't be pushed
+    #[clap(long, conflicts_with_all = &["owner", "token", "use-path"])]
+    pub no_fork: bool,
+
+    /// Clone the fork as a plain repository instead of adding a submodule
+    #[clap(long)]
+    pub no_submodule: bool,
+
+    /// Branch to create and check out in the fork, to start working on
+    #[clap(long, value_parser)]
+    pub new_branch: Option<String>,
+
+    /// Re-create existing forks and their patches from scratch, and reset the branch of
+    /// `--new-branch` if it already exists
+    #[clap(long)]
+    pub force: bool,
+
+    /// Name of the submodule in `.gitmodules` [default: its path]
+    #[clap(
+        long,
+        value_parser,
+        value_name = "NAME",
+        conflicts_with = "no-submodule"
+    )]
+    pub submodule_name: Option<String>,
+
+    /// Branch of the fork for the submodule to track with `git submodule update --remote`
+    #[clap(long, value_parser, conflicts_with = "no-submodule")]
+    pub track: Option<String>,
+
+    /// Name of the remote pointing at the original repository in the fork
+    #[clap(long, value_parser, default_value = "upstream")]
+    pub upstream_remote: String,
+
+    /// Name of the remote pointing at the fork in its clone
+    #[clap(long, value_parser, value_name = "NAME", default_value = "origin")]
+    pub remote_name: String,
+
+    /// Clone the fork over SSH, the default when the repository url is an SSH url
+    #[clap(long)]
+    pub ssh: bool,
+
+    /// Only fetch the latest commit of the fork, at the cost of not being able to check out
+    /// older revisions or tags without fetching them first. Requires the git command line tool
+    #[clap(long)]
+    pub shallow: bool,
+
+    /// Retry with a shallow clone when a full one runs out of disk space or time. Requires the
+    /// git command line tool for the retry
+    #[clap(long, conflicts_with = "shallow")]
+    pub shallow_fallback: bool,
+
+    /// Only fetch the branch of `--branch` or the default branch of the fork. Requires the git
+    /// command line tool
+    #[clap(long)]
+    pub single_branch: bool,
+
+    /// Partial clone filter like `blob:none`, to fetch objects when they are first needed.
+    /// Requires the git command line tool
+    #[clap(long, value_parser, value_name = "SPEC")]
+    pub filter: Option<String>,
+
+    /// Features to enable in the patch, separated by commas or spaces
+    #[clap(long, value_parser, use_value_delimiter = true, value_delimiter = ',')]
+    pub features: Vec<String>,
+
+    /// Write `default-features = false` into the patch
+    #[clap(long)]
+    pub no_default_features: bool,
+
+    /// Stop at the first dependency that can't be forked, removing the forks cloned so far and
+    /// leaving the manifest untouched
+    #[clap(long)]
+    pub atomic: bool,
+
+    /// Commit the manifest and the new submodules, with a message naming the forked
+    /// dependencies unless one is given
+    #[clap(
+        long,
+        value_parser,
+        value_name = "MESSAGE",
+        require_equals = true,
+        conflicts_with = "no-submodule"
+    )]
+    pub commit: Option<Option<String>>,
+
+    /// Write the manifest without showing the changes and asking for confirmation first
+    #[clap(short, long)]
+    pub yes: bool,
+
+    /// Run `cargo check` with the patches applied, offering to revert them if it fails
+    #[clap(long)]
+    pub check: bool,
+
+    /// Resolve the workspace again with the patches applied, updating the lockfile, and warn
+    /// about dependencies that don't resolve to their forks
+    #[clap(long)]
+    pub verify: bool,
+
+    /// Open the forks in `$EDITOR` or `$VISUAL` once they are patched in
+    #[clap(long)]
+    pub open_editor: bool,
+
+    /// How often to retry forking and cloning after a network error
+    #[clap(long, value_parser, default_value_t = 3)]
+    pub retries: u32,
+
+    /// Look up dependencies without network access, using `Cargo.lock` and the sources cargo
+    /// has cached
+    #[clap(long, global = true)]
+    pub offline: bool,
+
+    /// Only print warnings, errors and prompts
+    #[clap(short, long, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
+
+    /// Print the resolved urls and paths and every git step
+    #[clap(short, long, global = true)]
+    pub verbose: bool,
+
+    /// Coloring: auto, always, never
+    #[clap(
+        long,
+        value_enum,
+        value_name = "WHEN",
+        global = true,
+        default_value = "auto"
+    )]
+    pub color: ColorChoice,
+
+    /// Output format, `json` prints a record per forked dependency instead of messages
+    #[clap(long, value_enum, default_value = "human")]
+    pub message_format: MessageFormat,
+
+    /// Directory, relative to the manifest, to place the forks in [default: patches]
+    #[clap(long, value_parser)]
+    pub patch_dir: Option<PathBuf>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum Command {
+    /// Remove the patch and submodule created for a dependency
+    Unfork(Unfork),
+    /// List the patched dependencies and the state of their forks
+    Status(Status),
+    /// List the dependencies that can be forked
+    List(List),
+    /// Fetch the remotes of the forked submodules and fast-forward their branches
+    Sync(SyncForks),
+    /// Store a GitHub personal access token to fork through the API with
+    Login(Login),
+    /// Check the workspace, git and the GitHub token for problems that keep forking from working
+    Doctor(Doctor),
+    /// Print a completion script for `cargo forkdep` to stdout
+    #[clap(hide = true)]
+    Completions(Completions),
+}
+
+#[derive(clap::Args, Debug)]
+pub struct Unfork {
+    pub dependency: String,
+
+    #[clap(long, value_parser)]
+    pub manifest_path: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct Status {
+    #[clap(long, value_parser)]
+    pub manifest_path: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct SyncForks {
+    /// Only sync the fork of this dependency
+    #[clap(long, value_parser)]
+    pub dependency: Option<String>,
+
+    #[clap(long, value_parser)]
+    pub manifest_path: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct List {
+    /// Only list the dependencies of the workspace members themselves
+    #[clap(long, conflicts_with = "depth")]
+    pub direct_only: bool,
+
+    /// Only list dependencies up to this many levels below the workspace members
+    #[clap(long, value_parser)]
+    pub depth: Option<usize>,
+
+    #[clap(long, value_parser)]
+    pub manifest_path: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct Doctor {
+    #[clap(long, value_parser)]
+    pub manifest_path: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct Login {
+    /// The token, asked for when omitted to keep it out of the shell history
+    #[clap(value_parser)]
+    pub token: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct Completions {
+    #[clap(value_enum)]
+    pub shell: clap_complete::Shell,
+}
+
+impl GitRef {
+    fn name(&self) -> &str {
+        match self {
+            GitRef::Rev(name) | GitRef::Branch(name) | GitRef::Tag(name) => name,
+        }
+    }
+}
+
+impl Forkdep {
+    /// Runs the subcommand, or forks the dependencies when there is none.
+    pub fn run(mut self) -> Result<()> {
+        COLOR.store(self.color as u8, Ordering::Relaxed);
+        PROMPT_TIMEOUT.store(self.prompt_timeout.unwrap_or(0), Ordering::Relaxed);
+        let verbosity = self.verbosity();
+        match self.command.take() {
+            Some(Command::Unfork(unfork_args)) => unfork(unfork_args, verbosity),
+            Some(Command::Status(status_args)) => status(status_args),
+            Some(Command::Sync(sync_args)) => sync(sync_args, verbosity),
+            Some(Command::List(list_args)) => list(list_args, verbosity, self.offline),
+            Some(Command::Login(login_args)) => login(login_args),
+            Some(Command::Doctor(doctor_args)) => doctor(doctor_args, verbosity, self.offline),
+            Some(Command::Completions(completions_args)) => {
+                completions(completions_args);
+                Ok(())
+            }
+            None => fork(self),
+        }
+    }
+
+    fn verbosity(&self) -> Verbosity {
+        // Messages would get in the way of parsing the records
+        if self.message_format == MessageFormat::Json {
+            return Verbosity::Quiet;
+        }
+        match (self.quiet, self.verbose) {
+            (true, _) => Verbosity::Quiet,
+            (false, true) => Verbosity::Verbose,
+            (false, false) => Verbosity::Normal,
+        }
+    }
+
+    fn patch_dir(&self) -> &Path {
+        self.patch_dir
+            .as_deref()
+            .unwrap_or_else(|| Path::new("patches"))
+    }
+
+    /// Fills in the options not given on the command line from `defaults`.
+    fn apply_defaults(&mut self, defaults: Defaults) {
+        self.owner = self.owner.take().or(defaults.owner);
+        self.host = self.host.take().or(defaults.host);
+        self.patch_dir = self.patch_dir.take().or(defaults.patch_dir);
+        self.ssh |= defaults.ssh.unwrap_or(false);
+    }
+
+    /// The domain of the GitHub Enterprise server given with `--host`.
+    fn github_host(&self) -> Option<&str> {
+        let host = self.host.as_deref()?;
+        Some(split_git_url(host).map_or(host.trim_end_matches('/'), |(domain, _)| domain))
+    }
+
+    /// Whether the fork of the repository at `url` should be cloned over SSH.
+    fn use_ssh(&self, url: &str) -> bool {
+        self.ssh || is_ssh_url(url)
+    }
+
+    /// The version of `spec` to fork, from the spec itself or from `--version`.
+    fn version_for<'a>(&'a self, spec: &'a DependencySpec) -> Result<Option<&'a VersionReq>> {
+        match (&spec.version, &self.dep_version) {
+            (Some(_), Some(_)) => Err(anyhow!(
+                "{} gives a version of its own, which conflicts with --version",
+                spec
+            )
+            .into()),
+            (version, dep_version) => Ok(version.as_ref().or(dep_version.as_ref())),
+        }
+    }
+
+    fn git_ref(&self) -> Option<GitRef> {
+        let Forkdep {
+            rev, branch, tag, ..
+        } = self;
+        rev.clone()
+            .map(GitRef::Rev)
+            .or_else(|| branch.clone().map(GitRef::Branch))
+            .or_else(|| tag.clone().map(GitRef::Tag))
+    }
+
+    /// Whether cloning needs the git command line tool, for options git2 doesn't support.
+    fn uses_git_cli(&self) -> bool {
+        self.shallow || self.single_branch || self.filter.is_some()
+    }
+
+    /// The options for `git fetch` that git2 doesn't support, fetching only the latest commit
+    /// when `shallow` is set.
+    fn git_fetch_options(&self, shallow: bool) -> Vec<String> {
+        let mut options = Vec::new();
+        if shallow {
+            options.push("--depth=1".to_owned());
+        }
+        if let Some(filter) = &self.filter {
+            options.push(format!("--filter={filter}"));
+        }
+        options
+    }
+
+    /// The comment on the patches of `resolved`, naming where the local copy comes from.
+    fn provenance(&self, resolved: &ResolvedDependency) -> String {
+        if self.no_fork {
+            format!("forkdep: clone of {}", resolved.repository)
+        } else {
+            format!("forkdep: forked from {}", resolved.repository)
+        }
+    }
+
+    /// The ref to check out in the fork of `resolved`, the version tag is used without one.
+    fn git_ref_for(&self, resolved: &ResolvedDependency) -> Option<GitRef> {
+        match &resolved.locked_rev {
+            Some(rev) if self.git_ref_from_lock => Some(GitRef::Rev(rev.clone())),
+            _ => self.git_ref(),
+        }
+    }
+}
+
+/// A dependency to fork together with the repository it was published from.
+#[derive(Debug)]
+pub struct ResolvedDependency {
+    pub name: String,
+    /// The package in the lockfile, `None` for a repository given with `--url`.
+    pub id: Option<PackageId>,
+    pub repository: String,
+    /// Directory of the crate inside the repository, for crates living in a monorepo.
+    pub subdir: Option<PathBuf>,
+    /// Commit the lockfile pins a git dependency to.
+    pub locked_rev: Option<String>,
+}
+
+impl ResolvedDependency {
+    fn version(&self) -> Option<&Version> {
+        self.id.map(PackageId::version)
+    }
+
+    /// The path to patch the dependency with, given the path of the local copy of the repository.
+    fn crate_path(&self, dep_path: PathBuf) -> PathBuf {
+        match &self.subdir {
+            Some(subdir) => dep_path.join(subdir),
+            None => dep_path,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+/// Defaults for the command line options, read from `.cargo-forkdep.toml` in the workspace
+/// root or `cargo-forkdep/config.toml` in the user config directory.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct Defaults {
+    owner: Option<String>,
+    host: Option<String>,
+    patch_dir: Option<PathBuf>,
+    ssh: Option<bool>,
+}
+
+impl Defaults {
+    /// Reads the defaults, preferring the ones of the workspace over the ones of the user.
+    fn load(workspace_root: &Path) -> Result<Defaults> {
+        let files = [
+            Some(workspace_root.join(".cargo-forkdep.toml")),
+            dirs::config_dir().map(|dir| dir.join("cargo-forkdep").join("config.toml")),
+        ];
+        let mut defaults = Defaults::default();
+        for file in files.into_iter().flatten().filter(|file| file.exists()) {
+            let data = fs::read_to_string(&file)?;
+            let found: Defaults = toml_edit::easy::from_str(&data)
+                .map_err(|err| anyhow!("could not parse {}: {}", file.display(), err))?;
+            defaults = Defaults {
+                owner: defaults.owner.or(found.owner),
+                host: defaults.host.or(found.host),
+                patch_dir: defaults.patch_dir.or(found.patch_dir),
+                ssh: defaults.ssh.or(found.ssh),
+            };
+        }
+        Ok(defaults)
+    }
+}
+
+/// How the workspace members depend on a dependency, directly or through other packages.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DependencyKind {
+    Normal,
+    Dev,
+    Build,
+}
+
+impl DependencyKind {
+    fn from_cargo(kind: DepKind) -> DependencyKind {
+        match kind {
+            DepKind::Normal => DependencyKind::Normal,
+            DepKind::Development => DependencyKind::Dev,
+            DepKind::Build => DependencyKind::Build,
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            DependencyKind::Normal => "dependency",
+            DependencyKind::Dev => "dev-dependency",
+            DependencyKind::Build => "build-dependency",
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+/// The `--color` of the run, as a [`ColorChoice`], which the messages are printed with.
+static COLOR: AtomicU8 = AtomicU8::new(ColorChoice::Auto as u8);
+
+fn color_choice() -> ColorChoice {
+    match COLOR.load(Ordering::Relaxed) {
+        choice if choice == ColorChoice::Always as u8 => ColorChoice::Always,
+        choice if choice == ColorChoice::Never as u8 => ColorChoice::Never,
+        _ => ColorChoice::Auto,
+    }
+}
+
+/// Prints `parts` as a line of stdout, or of stderr with `stderr`, the ones with a color in
+/// bold and that color unless the output isn't colored.
+fn print_colored(stderr: bool, parts: &[(&str, Option<Color>)]) {
+    let stream = if stderr {
+        atty::Stream::Stderr
+    } else {
+        atty::Stream::Stdout
+    };
+    let colored = match color_choice() {
+        ColorChoice::Always => true,
+        ColorChoice::Auto => {
+            atty::is(stream)
+                && env::var_os("NO_COLOR").is_none()
+                && env::var_os("TERM").map_or(true, |term| term != "dumb")
+        }
+        ColorChoice::Never => false,
+    };
+    let mut buffer = if colored {
+        Buffer::ansi()
+    } else {
+        Buffer::no_color()
+    };
+    for (text, color) in parts {
+        if let Some(color) = color {
+            let _ = buffer.set_color(ColorSpec::new().set_fg(Some(*color)).set_bold(true));
+        }
+        let _ = write!(buffer, "{text}");
+        if color.is_some() {
+            let _ = buffer.reset();
+        }
+    }
+    // Through the macros, which tests capture
+    let line = String::from_utf8_lossy(buffer.as_slice());
+    if stderr {
+        eprintln!("{line}");
+    } else {
+        println!("{line}");
+    }
+}
+
+/// Prints `message` as a warning on stderr.
+fn warn(message: &str) {
+    print_colored(
+        true,
+        &[
+            ("warning", Some(Color::Yellow)),
+            (": ", None),
+            (message, None),
+        ],
+    );
+}
+
+/// Prints `message` as an error on stderr, for errors that don't end the run.
+fn print_error(message: &str) {
+    print_colored(
+        true,
+        &[("error", Some(Color::Red)), (": ", None), (message, None)],
+    );
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageFormat {
+    Human,
+    Json,
+}
+
+/// The outcome of forking a dependency, printed with `--message-format json`.
+#[derive(Serialize)]
+struct ForkRecord<'a> {
+    reason: &'static str,
+    name: &'a str,
+    version: Option<String>,
+    repository: &'a str,
+    fork_url: Option<String>,
+    path: String,
+    patch_table: &'a str,
+    checked_out: Option<String>,
+}
+
+impl ForkRecord<'_> {
+    fn print(&self) -> Result<()> {
+        let record = serde_json::to_string(self).map_err(anyhow::Error::from)?;
+        println!("{record}");
+        Ok(())
+    }
+}
+
+/// What forking a dependency did, summed up at the end of the run.
+struct Forked {
+    name: String,
+    table: String,
+    dep_path: PathBuf,
+    /// Whether the run cloned it, rather than reusing an earlier clone
+    cloned: bool,
+    /// What was done, `forked` or `cloned`
+    action: &'static str,
+    version: Option<Version>,
+    repository: String,
+    /// The url of the fork, or `None` when it couldn't be read from the clone
+    fork_url: Option<String>,
+    kind: &'static str,
+    checked_out: Option<String>,
+}
+
+impl Forked {
+    fn record(&self) -> Result<ForkRecord<'_>> {
+        Ok(ForkRecord {
+            reason: "dependency-forked",
+            name: &self.name,
+            version: self.version.as_ref().map(ToString::to_string),
+            repository: &self.repository,
+            fork_url: self.fork_url.clone(),
+            path: path_str(&self.dep_path)?.to_owned(),
+            patch_table: &self.table,
+            checked_out: self.checked_out.clone(),
+        })
+    }
+}
+
+#[derive(Debug)]
+enum GitRef {
+    Rev(String),
+    Branch(String),
+    Tag(String),
+}
+
+/// A dependency given on the command line, optionally with the version to fork.
+#[derive(Debug, Clone)]
+pub struct DependencySpec {
+    pub name: String,
+    pub version: Option<VersionReq>,
+}
+
+impl fmt::Display for DependencySpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.version {
+            Some(version) => write!(f, "{}@{version}", self.name),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+/// Parses a dependency like cargo's package specs, as `name` or `name@version`.
+fn parse_dependency_spec(spec: &str) -> Result<DependencySpec, String> {
+    let (name, version) = match spec.split_once('@') {
+        Some((name, version)) => {
+            let version = parse_version_req(version)
+                .map_err(|err| format!("invalid version `{version}`: {err}"))?;
+            (name, Some(version))
+        }
+        None => (spec, None),
+    };
+    if name.is_empty() {
+        return Err(format!("`{spec}` names no dependency"));
+    }
+    Ok(DependencySpec {
+        name: name.to_owned(),
+        version,
+    })
+}
+
+/// Parses a version requirement, treating a bare version like `1.0.190` as an exact match.
+fn parse_version_req(spec: &str) -> Result<VersionReq, semver::Error> {
+    match Version::parse(spec) {
+        Ok(version) => VersionReq::parse(&format!("={version}")),
+        Err(_) => VersionReq::parse(spec),
+    }
+}
+
+/// The cargo config, printing cargo's own messages at `verbosity`.
+fn cargo_config(verbosity: Verbosity, offline: bool) -> Result<Config> {
+    let mut config = Config::default()?;
+    config.configure(
+        u32::from(verbosity == Verbosity::Verbose),
+        verbosity == Verbosity::Quiet,
+        Some(match color_choice() {
+            ColorChoice::Auto => "auto",
+            ColorChoice::Always => "always",
+            ColorChoice::Never => "never",
+        }),
+        false,
+        false,
+        offline,
+        &None,
+        &[],
+        &[],
+    )?;
+    Ok(config)
+}
+
+fn fork(mut args: Forkdep) -> Result<()> {
+    let config = cargo_config(args.verbosity(), args.offline)?;
+    let member_manifest_path = resolve_manifest_path(args.manifest_path.clone())?;
+    let workspace = Workspace::new(&member_manifest_path, &config)?;
+    args.apply_defaults(Defaults::load(workspace.root())?);
+    if args.print_url {
+        return print_urls(&workspace, &args);
+    }
+    if let Some(url) = &args.url {
+        let name = match &args.package {
+            Some(name) => name.clone(),
+            None => repo_path(&normalize_repository_url(url))
+                .and_then(|path| path.split('/').next_back())
+                .filter(|name| !name.is_empty())
+                .ok_or_else(|| {
+                    anyhow!("could not tell the name of the crate at {url}, give it with --as")
+                })?
+                .to_owned(),
+        };
+        args.dependencies = vec![DependencySpec {
+            name,
+            version: None,
+        }];
+    }
+    if args.remote_name == args.upstream_remote && !args.no_fork {
+        return Err(anyhow!(
+            "--remote-name and --upstream-remote both name the remote {}",
+            args.remote_name
+        )
+        .into());
+    }
+    if args.use_path.is_some() && args.dependencies.len() > 1 {
+        return Err(anyhow!("--use-path patches a single dependency").into());
+    }
+    // Cargo ignores `[patch]` outside of the manifest of the workspace root
+    let manifest_path = workspace.root_manifest().to_owned();
+    if manifest_path != member_manifest_path && args.verbosity() >= Verbosity::Normal {
+        println!(
+            "{} is a workspace member, patching the workspace root {} instead",
+            member_manifest_path.display(),
+            manifest_path.display()
+        );
+    }
+    let mut manifest = read_manifest(&manifest_path)?;
+    let original = manifest.clone();
+    let root_dir = manifest_dir(&manifest_path)?;
+    if !args.no_submodule && Repository::discover(root_dir).is_err() {
+        if args.commit.is_some() || args.track.is_some() || args.submodule_name.is_some() {
+            return Err(anyhow!(
+                "{} is not in a git repository, which --commit, --track and --submodule-name need",
+                root_dir.display()
+            )
+            .into());
+        }
+        warn(&format!(
+            "{} is not in a git repository, cloning the forks without recording them as submodules",
+            root_dir.display()
+        ));
+        args.no_submodule = true;
+    }
+    let mut failed = Vec::new();
+    let mut forked = Vec::new();
+    let mut cloned = Vec::new();
+    for dependency in &args.dependencies {
+        match fork_dependency(&workspace, &mut manifest, root_dir, dependency, &args) {
+            Ok(Some(fork)) => {
+                if fork.cloned {
+                    cloned.push(fork.dep_path.clone());
+                }
+                forked.push(fork);
+            }
+            Ok(None) => {}
+            Err(err) => {
+                print_error(&format!("failed to fork {dependency}: {err}"));
+                failed.push(dependency.to_string());
+                if args.atomic {
+                    break;
+                }
+            }
+        }
+    }
+    let forked_paths: Vec<PathBuf> = forked.iter().map(|fork| fork.dep_path.clone()).collect();
+    let undo_clones = || {
+        for dep_path in cloned.iter().rev() {
+            if let Err(err) = remove_local_copy(root_dir, dep_path, args.no_submodule) {
+                warn(&format!("could not remove {}: {err}", dep_path.display()));
+            }
+        }
+    };
+    if args.atomic && !failed.is_empty() {
+        undo_clones();
+        return Err(anyhow!(
+            "could not fork {}, undid the other forks",
+            failed.join(", ")
+        )
+        .into());
+    }
+    if !args.dry_run && failed.len() < args.dependencies.len() {
+        let (old, new) = (original.to_string(), manifest.to_string());
+        if !args.yes && old != new {
+            eprintln!("changes to {}:", manifest_path.display());
+            print_diff(&old, &new);
+            match confirm("write these changes?") {
+                Ok(true) => {}
+                Ok(false) => {
+                    undo_clones();
+                    println!("left the manifest unchanged and removed the new forks");
+                    return Ok(());
+                }
+                Err(err) => {
+                    undo_clones();
+                    return Err(err);
+                }
+            }
+        }
+        write_manifest(&manifest_path, &manifest)?;
+        if args.verify {
+            verify_patches(&manifest_path, &forked, &args)?;
+        }
+        if args.check && !check_workspace(&manifest_path, args.verbosity())? {
+            // Nobody to ask with --yes, so the patches stay for the failure to be looked into
+            let revert = !args.yes
+                && confirm("cargo check failed with the patches applied, revert them?")
+                    .unwrap_or(false);
+            if revert {
+                write_manifest(&manifest_path, &original)?;
+                println!("reverted the manifest, the forks are kept and can be removed with `cargo forkdep unfork`");
+            } else {
+                eprintln!("kept the patches, remove them with `cargo forkdep unfork`");
+            }
+            return Err(anyhow!("cargo check failed with the patches applied").into());
+        }
+        if let Some(message) = &args.commit {
+            let names: Vec<String> = forked_paths
+                .iter()
+                .filter_map(|dep_path| dep_path.file_name())
+                .map(|name| name.to_string_lossy().into_owned())
+                .collect();
+            let message = message
+                .clone()
+                .unwrap_or_else(|| format!("forkdep: patch {} -> fork", names.join(", ")));
+            commit_forks(root_dir, &forked_paths, &message, args.verbosity())?;
+        }
+        if args.open_editor {
+            let forks: Vec<PathBuf> = forked_paths
+                .iter()
+                .map(|path| root_dir.join(path))
+                .collect();
+            open_editor(&forks)?;
+        }
+        match args.message_format {
+            // Only once the manifest is written, for the records to describe forks in use
+            MessageFormat::Json => {
+                for fork in &forked {
+                    fork.record()?.print()?;
+                }
+            }
+            MessageFormat::Human if args.verbosity() >= Verbosity::Normal => {
+                print_summary(&forked, args.dependencies.len());
+            }
+            MessageFormat::Human => {}
+        }
+    }
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!("could not fork {}", failed.join(", ")).into())
+    }
+}
+
+/// Prints a line for each of the `forked` dependencies, and how many of the `total` ones those
+/// are for a run forking more than one.
+fn print_summary(forked: &[Forked], total: usize) {
+    for fork in forked {
+        let version = fork
+            .version
+            .as_ref()
+            .map_or_else(String::new, |version| format!(" ({version})"));
+        let url = fork.fork_url.as_deref().map_or_else(
+            || "the fork".to_owned(),
+            |url| {
+                let url = normalize_repository_url(url);
+                url.strip_prefix("https://").unwrap_or(&url).to_owned()
+            },
+        );
+        let rest = format!(
+            ", {} {}, patched {}",
+            fork.kind,
+            fork.dep_path.display(),
+            patch_header(&fork.table)
+        );
+        print_colored(
+            false,
+            &[
+                (fork.action, Some(Color::Green)),
+                (&format!(" {}{version} -> ", fork.name), None),
+                (&url, Some(Color::Cyan)),
+                (&rest, None),
+            ],
+        );
+    }
+    if total > 1 {
+        let count = if forked.len() == total {
+            format!("forked {total} dependencies")
+        } else {
+            format!("forked {} of {total} dependencies", forked.len())
+        };
+        println!("{count}");
+    }
+}
+
+/// Prints the repository url of each dependency on a line of its own, for use in scripts.
+fn print_urls(workspace: &Workspace, args: &Forkdep) -> Result<()> {
+    for dependency in &args.dependencies {
+        // Nothing but the urls on stdout
+        let resolved = resolve_repository(
+            workspace,
+            &dependency.name,
+            args.version_for(dependency)?,
+            args.dep_kind,
+            args.registry.as_deref(),
+            args.dep_source.as_deref(),
+            Verbosity::Quiet,
+        )?;
+        println!("{}", resolved.repository);
+    }
+    Ok(())
+}
+
+/// Forks `dependency` and patches it in `manifest`, returning what was done, or `None` for a dry
+/// run.
+fn fork_dependency(
+    workspace: &Workspace,
+    manifest: &mut Document,
+    root_dir: &Path,
+    dependency: &DependencySpec,
+    args: &Forkdep,
+) -> Result<Option<Forked>> {
+    let verbosity = args.verbosity();
+    let resolved = match &args.url {
+        Some(url) => {
+            let (repository, subdir) = split_repository_url(&normalize_repository_url(url));
+            ResolvedDependency {
+                name: dependency.name.clone(),
+                id: None,
+                repository,
+                subdir,
+                locked_rev: None,
+            }
+        }
+        None => resolve_repository(
+            workspace,
+            &dependency.name,
+            args.version_for(dependency)?,
+            args.dep_kind,
+            args.registry.as_deref(),
+            args.dep_source.as_deref(),
+            verbosity,
+        )?,
+    };
+    // Cargo patches packages by their own name, not by the key of a renamed dependency
+    let dependency = resolved.name.as_str();
+    if args.git_ref_from_lock && resolved.locked_rev.is_none() {
+        warn(&format!(
+            "{dependency} is not a git dependency, checking out its version tag"
+        ));
+    }
+    let existing = existing_patch_table(manifest, dependency);
+    let table = match (args.patch_table.as_str(), existing) {
+        // Keep an existing patch where it is, a second one for another source would be ignored
+        ("auto", Some(existing)) => existing,
+        ("auto", None) => resolved.id.map_or_else(
+            // Nothing depends on it yet, and most crates come from crates.io
+            || "crates-io".to_owned(),
+            |id| patch_table(id.source_id()),
+        ),
+        (table, existing) => {
+            if let Some(existing) = existing.filter(|existing| existing != table) {
+                warn(&format!(
+                    "{dependency} is also patched in {}, which is left as it is",
+                    patch_header(&existing)
+                ));
+            }
+            table.to_owned()
+        }
+    };
+    if let Some(id) = resolved.id {
+        if !table_matches_source(workspace.config(), &table, id.source_id()) {
+            warn(&format!(
+                "{dependency} comes from {}, so a patch in {} won't apply to it, \
+                 use --patch-table {} instead",
+                id.source_id(),
+                patch_header(&table),
+                patch_table(id.source_id())
+            ));
+        }
+    }
+    if let Some(dir) = &args.use_path {
+        patch_to_checkout(manifest, root_dir, dir, &resolved, &table, args)?;
+        return Ok(None);
+    }
+    if args.dry_run {
+        print_plan(&resolved, &table, root_dir, dependency, args)?;
+        return Ok(None);
+    }
+    let dep_path = local_copy_path(args.patch_dir(), dependency);
+    let patch_path = relative_path(root_dir, &resolved.crate_path(dep_path.clone()));
+    // Patch a copy first, so that a manifest we can't patch doesn't leave a fork behind
+    let mut patched = manifest.clone();
+    // Drop what the old patch had on top of the path, like features
+    let replaced = args.force && remove_patch(&mut patched, dependency).is_ok();
+    let updated = apply_patch(
+        &mut patched,
+        &table,
+        &patch_path,
+        dependency,
+        &args.features,
+        args.no_default_features,
+        Some(&args.provenance(&resolved)),
+    )?;
+    let (repo, cloned) = fork_and_clone(&resolved, root_dir, &dep_path, args)?;
+    let set_up = (|| {
+        // A clone of the original repository already has it as its origin
+        if !args.no_fork {
+            add_upstream_remote(
+                &repo,
+                &args.upstream_remote,
+                &resolved.repository,
+                verbosity,
+            )?;
+        }
+        if let Some(branch) = &args.new_branch {
+            create_branch(&repo, branch, args.force, verbosity)?;
+        }
+        if let Some(branch) = &args.track {
+            track_branch(root_dir, &dep_path, branch)?;
+        }
+        warn_incompatible_fork(&resolved, &root_dir.join(&patch_path));
+        if args.siblings {
+            patch_siblings(
+                workspace,
+                &mut patched,
+                root_dir,
+                &root_dir.join(&patch_path),
+                &resolved,
+                args,
+            )?;
+        }
+        Ok(())
+    })();
+    if let Err(err) = set_up {
+        // Only what this run cloned, an earlier clone stays as it was found
+        if cloned {
+            drop(repo);
+            let cleanup = remove_local_copy(root_dir, &dep_path, args.no_submodule);
+            return Err(undo_failed_clone(err, cleanup));
+        }
+        return Err(err);
+    }
+    *manifest = patched;
+    // The configured url, libgit2 would apply `insteadOf` rewrites to the one of the remote
+    let fork_url = repo
+        .config()?
+        .get_string(&format!("remote.{}.url", args.remote_name))
+        .ok();
+    if verbosity < Verbosity::Normal {
+        // Nothing to report
+    } else if updated || replaced {
+        println!(
+            "updating the patch of {dependency} in {}",
+            patch_header(&table)
+        );
+    } else {
+        println!(
+            "adding a patch for {dependency} to {}",
+            patch_header(&table)
+        );
+    }
+    Ok(Some(Forked {
+        name: dependency.to_owned(),
+        table,
+        dep_path,
+        cloned,
+        action: if args.no_fork { "cloned" } else { "forked" },
+        version: resolved.version().cloned(),
+        repository: resolved.repository.clone(),
+        fork_url,
+        checked_out: describe_head(&repo).ok(),
+        kind: if args.no_submodule {
+            "clone"
+        } else {
+            "submodule"
+        },
+    }))
+}
+
+/// Patches the other members of the workspace the fork of `resolved` at `crate_dir` belongs to,
+/// as far as `workspace` depends on them, in the tables of their sources.
+fn patch_siblings(
+    workspace: &Workspace,
+    manifest: &mut Document,
+    root_dir: &Path,
+    crate_dir: &Path,
+    resolved: &ResolvedDependency,
+    args: &Forkdep,
+) -> Result<()> {
+    let verbosity = args.verbosity();
+    let fork_workspace = match Workspace::new(&crate_dir.join("Cargo.toml"), workspace.config()) {
+        Ok(fork_workspace) => fork_workspace,
+        // A crate that isn't in a workspace of its own is taken for one in ours
+        Err(err) => {
+            if verbosity == Verbosity::Verbose {
+                println!("found no siblings of {}: {err}", resolved.name);
+            }
+            return Ok(());
+        }
+    };
+    let lockfile = load_lockfile(workspace)?;
+    let members: HashSet<PackageId> = workspace.members().map(|m| m.package_id()).collect();
+    for sibling in fork_workspace.members() {
+        let name = sibling.name();
+        if name == resolved.name.as_str() {
+            continue;
+        }
+        let mut tables: Vec<String> = lockfile
+            .iter()
+            .filter(|id| id.name() == name && !members.contains(id))
+            .map(|id| match args.patch_table.as_str() {
+                "auto" => existing_patch_table(manifest, &name)
+                    .unwrap_or_else(|| patch_table(id.source_id())),
+                table => table.to_owned(),
+            })
+            .collect();
+        tables.sort();
+        tables.dedup();
+        let path = relative_path(root_dir, sibling.root());
+        for table in tables {
+            apply_patch(
+                manifest,
+                &table,
+                &path,
+                &name,
+                &[],
+                false,
+                Some(&args.provenance(resolved)),
+            )?;
+            if verbosity >= Verbosity::Normal {
+                println!(
+                    "adding a patch for {name}, a sibling of {}, to {}",
+                    resolved.name,
+                    patch_header(&table)
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Patches the dependency of `resolved` in `manifest` to the crate in `dir`, a checkout made
+/// by hand.
+fn patch_to_checkout(
+    manifest: &mut Document,
+    root_dir: &Path,
+    dir: &Path,
+    resolved: &ResolvedDependency,
+    table: &str,
+    args: &Forkdep,
+) -> Result<()> {
+    let dependency = resolved.name.as_str();
+    let dir = dir
+        .canonicalize()
+        .map_err(|err| anyhow!("could not use {}: {err}", dir.display()))?;
+    // The checkout may be of the whole repository the crate was published from
+    let crate_dir = match &resolved.subdir {
+        Some(subdir) if dir.join(subdir).join("Cargo.toml").is_file() => dir.join(subdir),
+        _ => dir,
+    };
+    if crate_name(&crate_dir)?.as_deref() != Some(dependency) {
+        return Err(ForkdepError::MissingCrate {
+            dependency: dependency.to_owned(),
+            path: crate_dir,
+        });
+    }
+    let patch_path =
+        pathdiff::diff_paths(&crate_dir, root_dir.canonicalize()?).unwrap_or(crate_dir.clone());
+    if args.dry_run {
+        println!(
+            "would patch {dependency} in {} to {}",
+            patch_header(table),
+            patch_path.display()
+        );
+        return Ok(());
+    }
+    apply_patch(
+        manifest,
+        table,
+        &patch_path,
+        dependency,
+        &args.features,
+        args.no_default_features,
+        Some(&format!("forkdep: checkout of {}", resolved.repository)),
+    )?;
+    warn_incompatible_fork(resolved, &crate_dir);
+    if args.verbosity() >= Verbosity::Normal {
+        println!(
+            "patching {dependency} in {} to {}",
+            patch_header(table),
+            patch_path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Warns when the version of the fork can't replace the locked version, as cargo then
+/// ignores the patch.
+fn warn_incompatible_fork(resolved: &ResolvedDependency, crate_dir: &Path) {
+    let locked = match resolved.version() {
+        Some(locked) => locked,
+        None => return,
+    };
+    let fork_version = match crate_version(crate_dir) {
+        Ok(Some(version)) => version,
+        // Nothing to compare, cargo will complain if the crate is unusable
+        Ok(None) | Err(_) => return,
+    };
+    if !compatible_req(locked).matches(&fork_version) {
+        warn(&format!(
+            "the fork of {} is at version {}, which is not compatible with the locked version {}, \
+             so the patch won't take effect. Bump the version of the fork or check out a matching tag",
+            resolved.name,
+            fork_version,
+            locked
+        ));
+    }
+}
+
+/// The version in the manifest of the crate at `crate_dir`, if it is set explicitly.
+fn crate_version(crate_dir: &Path) -> Result<Option<Version>> {
+    let manifest = read_manifest(&crate_dir.join("Cargo.toml"))?;
+    let version = manifest
+        .get("package")
+        .and_then(|package| package.get("version"))
+        .and_then(|version| version.as_str());
+    Ok(match version {
+        Some(version) => Some(Version::parse(version).map_err(anyhow::Error::from)?),
+        None => None,
+    })
+}
+
+/// The name of the package in `crate_dir`, if there is one.
+fn crate_name(crate_dir: &Path) -> Result<Option<String>> {
+    let manifest_path = crate_dir.join("Cargo.toml");
+    if !manifest_path.is_file() {
+        return Ok(None);
+    }
+    let manifest = read_manifest(&manifest_path)?;
+    Ok(manifest
+        .get("package")
+        .and_then(|package| package.get("name"))
+        .and_then(|name| name.as_str())
+        .map(str::to_owned))
+}
+
+/// The versions semver compatible with `version`, which a patch must be in to replace it.
+fn compatible_req(version: &Version) -> VersionReq {
+    let (op, minor, patch) = match (version.major, version.minor) {
+        (0, 0) => (Op::Exact, Some(0), Some(version.patch)),
+        (0, minor) => (Op::Caret, Some(minor), None),
+        (_, _) => (Op::Caret, None, None),
+    };
+    VersionReq {
+        comparators: vec![Comparator {
+            op,
+            major: version.major,
+            minor,
+            patch,
+            pre: Prerelease::EMPTY,
+        }],
+    }
+}
+
+fn print_plan(
+    resolved: &ResolvedDependency,
+    table: &str,
+    root_dir: &Path,
+    dependency: &str,
+    args: &Forkdep,
+) -> Result<()> {
+    let host = Host::from_url(&resolved.repository, args.github_host());
+    let fork_url = match (host, &args.owner) {
+        _ if args.no_fork => Some(resolved.repository.clone()),
+        (Some(host), Some(owner)) => fork_url(
+            &host,
+            &resolved.repository,
+            owner,
+            args.use_ssh(&resolved.repository),
+        )
+        .map(Some)?,
+        _ => None,
+    };
+    let dep_path = local_copy_path(args.patch_dir(), dependency);
+    if args.message_format == MessageFormat::Json {
+        return ForkRecord {
+            reason: "fork-planned",
+            name: dependency,
+            version: resolved.version().map(ToString::to_string),
+            repository: &resolved.repository,
+            fork_url,
+            path: path_str(&dep_path)?.to_owned(),
+            patch_table: table,
+            checked_out: args
+                .git_ref_for(resolved)
+                .map(|git_ref| git_ref.name().to_owned()),
+        }
+        .print();
+    }
+    let fork_url = fork_url.unwrap_or_else(|| "decided when forking".to_owned());
+    let patch_path = relative_path(root_dir, &resolved.crate_path(dep_path.clone()));
+    let mut patch = Document::new();
+    apply_patch(
+        &mut patch,
+        table,
+        &patch_path,
+        dependency,
+        &args.features,
+        args.no_default_features,
+        Some(&args.provenance(resolved)),
+    )?;
+    match resolved.version() {
+        Some(version) => println!("{dependency} {version}:"),
+        None => println!("{dependency}:"),
+    }
+    if args.no_fork {
+        println!("  clone {}", resolved.repository);
+    } else {
+        println!("  fork {}", resolved.repository);
+        println!("  fork url: {fork_url}");
+        println!("  upstream remote: {}", args.upstream_remote);
+    }
+    let kind = if args.no_submodule {
+        "clone"
+    } else {
+        "submodule"
+    };
+    println!("  {kind}: {}", dep_path.display());
+    println!("  patch:");
+    for line in patch.to_string().lines().filter(|line| !line.is_empty()) {
+        println!("    {line}");
+    }
+    Ok(())
+}
+
+/// `path` relative to `base`, so that the manifest stays portable between machines.
+fn relative_path(base: &Path, path: &Path) -> PathBuf {
+    pathdiff::diff_paths(base.join(path), base).unwrap_or_else(|| path.to_owned())
+}
+
+fn local_copy_path(patch_dir: &Path, dep_name: &str) -> PathBuf {
+    patch_dir.join(dep_name)
+}
+
+fn unfork(args: Unfork, verbosity: Verbosity) -> Result<()> {
+    let manifest_path = root_manifest_path(args.manifest_path)?;
+    let mut manifest = read_manifest(&manifest_path)?;
+    let comment = patch_comment(&manifest, &args.dependency);
+    let dep_path = remove_patch(&mut manifest, &args.dependency)?;
+    let root_dir = manifest_dir(&manifest_path)?;
+    let patch_dir = Defaults::load(root_dir)?
+        .patch_dir
+        .unwrap_or_else(|| "patches".into());
+    let made_by_forkdep = match comment.as_deref() {
+        // Given with --use-path, so it's the user's own
+        Some(comment) if comment.starts_with("forkdep: checkout of") => false,
+        Some(comment) if comment.starts_with("forkdep: ") => true,
+        _ => dep_path.starts_with(&patch_dir),
+    };
+    let local_copy = if made_by_forkdep {
+        find_local_copy(root_dir, &dep_path)?
+    } else {
+        None
+    };
+    // Still patching the other crates of a fork made with --siblings
+    let shared = local_copy
+        .as_ref()
+        .filter(|(copy, _)| patches_into(&manifest, copy));
+    if let (Some((copy, _)), true) = (shared, verbosity >= Verbosity::Normal) {
+        println!("keeping {}, other patches point into it", copy.display());
+    }
+    match local_copy {
+        _ if shared.is_some() => {}
+        Some((_, LocalCopy::Submodule(root_repo, submodule_path))) => {
+            remove_submodule(&root_repo, &submodule_path)?
+        }
+        Some((copy, LocalCopy::Clone)) => remove_dir_and_empty_parents(root_dir, &copy)?,
+        None if !made_by_forkdep => warn(&format!(
+            "left {} as it is, it wasn't made by forkdep",
+            dep_path.display()
+        )),
+        None if root_dir.join(&dep_path).exists() => warn(&format!(
+            "left {} as it is, it isn't in a submodule or clone in the workspace",
+            dep_path.display()
+        )),
+        None => {}
+    }
+    write_manifest(&manifest_path, &manifest)?;
+    if verbosity >= Verbosity::Normal {
+        println!("removed the patch and fork of {}", args.dependency);
+    }
+    Ok(())
+}
+
+/// Resolves the workspace at `manifest_path` again, updating the lockfile, and warns about the
+/// `forked` dependencies that don't resolve to their forks.
+fn verify_patches(manifest_path: &Path, forked: &[Forked], args: &Forkdep) -> Result<()> {
+    let config = cargo_config(args.verbosity(), args.offline)?;
+    let workspace = Workspace::new(manifest_path, &config)?;
+    // Unlike `generate_lockfile`, this keeps the versions of the other dependencies as they are
+    let (_, resolve) = resolve_ws(&workspace)?;
+    for fork in forked {
+        let unpatched: Vec<PackageId> = resolve
+            .iter()
+            .filter(|id| id.name() == fork.name.as_str() && !id.source_id().is_path())
+            .collect();
+        if unpatched.is_empty() && args.verbosity() >= Verbosity::Normal {
+            println!("verified that {} resolves to its fork", fork.name);
+        }
+        for id in unpatched {
+            let reason = if table_matches_source(&config, &fork.table, id.source_id()) {
+                "most likely the version of the fork doesn't match the required one".to_owned()
+            } else {
+                format!(
+                    "{} doesn't patch {}, use --patch-table {} instead",
+                    patch_header(&fork.table),
+                    id.source_id(),
+                    patch_table(id.source_id())
+                )
+            };
+            warn(&format!(
+                "{} still resolves to version {} from {}, so its patch has no effect: {reason}",
+                fork.name,
+                id.version(),
+                id.source_id()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Runs `cargo check` on the workspace of `manifest_path`, returning whether it succeeded.
+fn check_workspace(manifest_path: &Path, verbosity: Verbosity) -> Result<bool> {
+    // Cargo tells its subcommands which binary it is running as
+    let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    let mut command = std::process::Command::new(cargo);
+    command
+        .arg("check")
+        .arg("--workspace")
+        .arg("--manifest-path")
+        .arg(manifest_path);
+    match verbosity {
+        Verbosity::Quiet => command.arg("--quiet"),
+        Verbosity::Normal => &mut command,
+        Verbosity::Verbose => command.arg("--verbose"),
+    };
+    let status = command
+        .status()
+        .map_err(|err| anyhow!("could not run cargo check: {}", err))?;
+    Ok(status.success())
+}
+
+fn confirm(question: &str) -> Result<bool> {
+    let answer = prompt(
+        &format!("{question} [y/N] "),
+        "a confirmation",
+        "pass --yes to go ahead without asking",
+    )?;
+    Ok(matches!(answer.as_str(), "y" | "Y" | "yes"))
+}
+
+/// Prints the lines removed from `old` and added in `new` on stderr, away from the records of
+/// `--message-format json`.
+fn print_diff(old: &str, new: &str) {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    // Lengths of the longest common subsequences of the tails of both
+    let mut common = vec![vec![0; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            i += 1;
+            j += 1;
+        } else if j < new.len() && (i == old.len() || common[i][j + 1] >= common[i + 1][j]) {
+            eprintln!("{}", format!("+ {}", new[j]).trim_end());
+            j += 1;
+        } else {
+            eprintln!("{}", format!("- {}", old[i]).trim_end());
+            i += 1;
+        }
+    }
+}
+
+/// Opens `paths` in the editor from `$EDITOR` or `$VISUAL`, or prints them when neither is set.
+fn open_editor(paths: &[PathBuf]) -> Result<()> {
+    let editor = ["EDITOR", "VISUAL"]
+        .into_iter()
+        .filter_map(|var| env::var(var).ok())
+        .find(|editor| !editor.trim().is_empty());
+    let editor = match editor {
+        Some(editor) => editor,
+        None => {
+            println!("neither $EDITOR nor $VISUAL is set, the forks are at:");
+            for path in paths {
+                println!("  {}", path.display());
+            }
+            return Ok(());
+        }
+    };
+    // Editors are often configured with arguments, like `code --wait`
+    let mut words = editor.split_whitespace();
+    let program = words.next().unwrap_or_default();
+    let status = std::process::Command::new(program)
+        .args(words)
+        .args(paths)
+        .status()
+        .map_err(|err| anyhow!("could not start the editor `{}`: {}", editor, err))?;
+    if !status.success() {
+        return Err(anyhow!("the editor `{}` failed with {}", editor, status).into());
+    }
+    Ok(())
+}
+
+fn status(args: Status) -> Result<()> {
+    let manifest_path = root_manifest_path(args.manifest_path)?;
+    let manifest = read_manifest(&manifest_path)?;
+    let root_dir = manifest_dir(&manifest_path)?;
+    let root_repo = Repository::discover(root_dir).ok();
+    let mut rows = vec![[
+        "dependency".to_owned(),
+        "path".to_owned(),
+        "url".to_owned(),
+        "checked out".to_owned(),
+        "problems".to_owned(),
+    ]];
+    for (dep, path) in path_patches(&manifest) {
+        let (url, head, problem) = match fork_state(root_repo.as_ref(), &root_dir.join(&path)) {
+            Ok((url, head)) => (url, head, String::new()),
+            Err(err) => (String::new(), String::new(), err.to_string()),
+        };
+        rows.push([dep, path, url, head, problem]);
+    }
+    if rows.len() == 1 {
+        println!("no patches in {}", manifest_path.display());
+        return Ok(());
+    }
+    print_table(&rows);
+    Ok(())
+}
+
+fn sync(args: SyncForks, verbosity: Verbosity) -> Result<()> {
+    let manifest_path = root_manifest_path(args.manifest_path)?;
+    let manifest = read_manifest(&manifest_path)?;
+    let root_dir = manifest_dir(&manifest_path)?;
+    let patches: Vec<(String, String)> = path_patches(&manifest)
+        .into_iter()
+        .filter(|(dep, _)| args.dependency.as_ref().map_or(true, |name| name == dep))
+        .collect();
+    if let (Some(dependency), true) = (&args.dependency, patches.is_empty()) {
+        return Err(anyhow!(
+            "{} is not patched with a path in {}",
+            dependency,
+            manifest_path.display()
+        )
+        .into());
+    }
+    let root_repo = Repository::discover(root_dir).ok();
+    let mut failed = Vec::new();
+    let mut synced_copies: Vec<(PathBuf, &str)> = Vec::new();
+    for (dep, path) in &patches {
+        let copy = find_local_copy(root_dir, Path::new(path)).and_then(|copy| {
+            copy.map(|(copy, _)| copy)
+                .ok_or_else(|| anyhow!("{path} is not in a submodule or clone").into())
+        });
+        let copy = match copy {
+            Ok(copy) => copy,
+            Err(err) => {
+                print_error(&format!("failed to sync {dep}: {err}"));
+                failed.push(dep.as_str());
+                continue;
+            }
+        };
+        // The crates of a monorepo share one local copy
+        if let Some((_, first)) = synced_copies.iter().find(|(synced, _)| *synced == copy) {
+            if verbosity >= Verbosity::Normal {
+                println!("{dep}: synced together with {first}");
+            }
+            continue;
+        }
+        let synced = Repository::open(root_dir.join(&copy))
+            .map_err(Into::into)
+            .and_then(|repo| sync_fork(&repo, root_repo.as_ref(), verbosity));
+        synced_copies.push((copy, dep));
+        match synced {
+            Ok(outcome) => {
+                if verbosity >= Verbosity::Normal {
+                    println!("{dep}: {outcome}");
+                }
+            }
+            Err(err) => {
+                print_error(&format!("failed to sync {dep}: {err}"));
+                failed.push(dep.as_str());
+            }
+        }
+    }
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!("could not sync {}", failed.join(", ")).into())
+    }
+}
+
+/// Fetches all remotes of the fork in `repo` and fast-forwards its checked out branch to the
+/// branch it tracks, or else the branch of the same name of the fork, returning a description of
+/// what happened.
+fn sync_fork(
+    repo: &Repository,
+    root_repo: Option<&Repository>,
+    verbosity: Verbosity,
+) -> Result<String> {
+    let quiet = verbosity == Verbosity::Quiet;
+    let dir = repo
+        .workdir()
+        .ok_or_else(|| anyhow!("the fork is a bare repository"))?;
+    let remotes = repo.remotes()?;
+    let token = github_token(None);
+    for name in remotes.iter().flatten() {
+        if verbosity == Verbosity::Verbose {
+            println!("fetching {name} in {}", dir.display());
+        }
+        // libgit2 can't fetch into shallow or partial clones
+        let partial = repo
+            .config()?
+            .get_bool(&format!("remote.{name}.promisor"))
+            .unwrap_or(false);
+        if repo.is_shallow() || partial {
+            run_git(
+                dir,
+                &["fetch", if quiet { "--quiet" } else { "--progress" }, name],
+            )?;
+            continue;
+        }
+        repo.find_remote(name)?.fetch(
+            &[] as &[&str],
+            Some(&mut fetch_options(quiet, token.clone(), None)),
+            None,
+        )?;
+        finish_progress(quiet);
+    }
+    if repo.head_detached()? {
+        return Ok(format!(
+            "fetched, {} is detached so there is nothing to fast-forward",
+            describe_head(repo)?
+        ));
+    }
+    let head = repo.head()?;
+    let name = head
+        .shorthand()
+        .ok_or_else(|| anyhow!("the checked out branch is not valid utf-8"))?;
+    let mut branch = repo.find_branch(name, BranchType::Local)?;
+    let upstream = match branch.upstream() {
+        Ok(upstream) => upstream,
+        Err(_) => {
+            let remote = fork_remote(repo, root_repo)
+                .and_then(|remote| remote.name().map(str::to_owned))
+                .unwrap_or_else(|| "origin".to_owned());
+            repo.find_branch(&format!("{remote}/{name}"), BranchType::Remote)
+                .map_err(|_| {
+                    anyhow!(
+                        "{} tracks no branch and {} has no branch {}",
+                        name,
+                        remote,
+                        name
+                    )
+                })?
+        }
+    };
+    let upstream_name = upstream.name()?.unwrap_or("upstream").to_owned();
+    let local = head.peel_to_commit()?;
+    let target = upstream.get().peel_to_commit()?;
+    if local.id() == target.id() {
+        return Ok(format!("{name} is up to date with {upstream_name}"));
+    }
+    if repo.graph_descendant_of(local.id(), target.id())? {
+        return Ok(format!("{name} is ahead of {upstream_name}"));
+    }
+    if !repo.graph_descendant_of(target.id(), local.id())? {
+        return Err(anyhow!(
+            "{} has diverged from {}, merge or rebase it by hand",
+            name,
+            upstream_name
+        )
+        .into());
+    }
+    // The default safe checkout refuses to overwrite local changes
+    repo.checkout_tree(target.as_object(), None)?;
+    branch.get_mut().set_target(
+        target.id(),
+        &format!("forkdep: fast-forward to {upstream_name}"),
+    )?;
+    let short_id = target.as_object().short_id()?;
+    Ok(format!(
+        "fast-forwarded {name} to {upstream_name} ({})",
+        short_id.as_str().unwrap_or_default()
+    ))
+}
+
+fn list(args: List, verbosity: Verbosity, offline: bool) -> Result<()> {
+    let config = cargo_config(verbosity, offline)?;
+    let manifest_path = resolve_manifest_path(args.manifest_path)?;
+    let workspace = Workspace::new(&manifest_path, &config)?;
+    let lockfile = load_lockfile(&workspace)?;
+    let members: HashSet<PackageId> = workspace.members().map(|m| m.package_id()).collect();
+    let max_depth = if args.direct_only {
+        Some(1)
+    } else {
+        args.depth
+    };
+    let mut found = members.clone();
+    let mut queue: VecDeque<(PackageId, usize)> = members.iter().map(|id| (*id, 0)).collect();
+    while let Some((id, depth)) = queue.pop_front() {
+        if max_depth.map_or(false, |max_depth| depth >= max_depth) {
+            continue;
+        }
+        for (dep, _) in lockfile.deps(id) {
+            if found.insert(dep) {
+                queue.push_back((dep, depth + 1));
+            }
+        }
+    }
+    let mut deps: Vec<PackageId> = found.difference(&members).copied().collect();
+    deps.sort();
+    // Path dependencies are already local, so there is nothing to download for them
+    let (local, remote): (Vec<PackageId>, Vec<PackageId>) =
+        deps.iter().partition(|id| id.source_id().is_path());
+    let pkg_set = package_set(&config, &remote)?;
+    let packages = pkg_set.get_many(remote.iter().copied())?;
+    let mut rows = vec![[
+        "dependency".to_owned(),
+        "version".to_owned(),
+        "repository".to_owned(),
+    ]];
+    for id in &deps {
+        let repository = if local.contains(id) {
+            "local path".to_owned()
+        } else {
+            let package = packages
+                .iter()
+                .find(|package| package.package_id() == *id)
+                .ok_or_else(|| anyhow!("could not download {}", id))?;
+            let metadata = package.manifest().metadata();
+            match (&metadata.repository, &metadata.homepage) {
+                (Some(repository), _) => repository.clone(),
+                (None, Some(homepage)) => format!("{homepage} (homepage)"),
+                (None, None) => "none".to_owned(),
+            }
+        };
+        rows.push([id.name().to_string(), id.version().to_string(), repository]);
+    }
+    print_table(&rows);
+    Ok(())
+}
+
+/// Prints `rows` as left aligned columns, the first row being the header.
+fn print_table<const N: usize>(rows: &[[String; N]]) {
+    let mut widths = [0; N];
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    for row in rows {
+        let line: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+}
+
+/// The dependencies patched with a path in any `[patch]` table, together with that path.
+fn path_patches(manifest: &Document) -> Vec<(String, String)> {
+    let mut patches = Vec::new();
+    let tables = manifest.get("patch").and_then(Item::as_table_like);
+    for (_, table) in tables.into_iter().flat_map(|patch| patch.iter()) {
+        let entries = table
+            .as_table_like()
+            .into_iter()
+            .flat_map(|table| table.iter());
+        for (dep, entry) in entries {
+            if let Some(path) = entry.get("path").and_then(Item::as_str) {
+                patches.push((dep.to_owned(), path.to_owned()));
+            }
+        }
+    }
+    patches
+}
+
+/// The url of the fork checked out at `path` and a description of its HEAD.
+fn fork_state(root_repo: Option<&Repository>, path: &Path) -> Result<(String, String)> {
+    if !path.exists() {
+        return Err(anyhow!("path does not exist").into());
+    }
+    let repo = Repository::discover(path).map_err(|_| anyhow!("not in a git repository"))?;
+    let is_root = |root_repo: &Repository| {
+        root_repo.workdir().and_then(|dir| dir.canonicalize().ok())
+            == repo.workdir().and_then(|dir| dir.canonicalize().ok())
+    };
+    if root_repo.map_or(false, is_root) {
+        return Err(anyhow!("submodule missing, run `git submodule update --init`").into());
+    }
+    // As configured rather than rewritten, like the url in `.gitmodules`
+    let url = fork_remote(&repo, root_repo)
+        .and_then(|remote| {
+            let key = format!("remote.{}.url", remote.name()?);
+            repo.config().ok()?.get_string(&key).ok()
+        })
+        .or_else(|| submodule_url(root_repo?, &repo))
+        .unwrap_or_default();
+    Ok((url, describe_head(&repo)?))
+}
+
+/// The remote of the fork in `repo`, which may have been renamed with `--remote-name`. Found by
+/// the branch tracking it, or else as the only remote or the one the submodule was added with.
+fn fork_remote<'r>(repo: &'r Repository, root_repo: Option<&Repository>) -> Option<Remote<'r>> {
+    if let Ok(remote) = repo.find_remote("origin") {
+        return Some(remote);
+    }
+    let tracked = repo.head().ok().and_then(|head| {
+        let name = repo.branch_upstream_remote(head.name()?).ok()?;
+        name.as_str().map(str::to_owned)
+    });
+    if let Some(name) = tracked {
+        return repo.find_remote(&name).ok();
+    }
+    let remotes = repo.remotes().ok()?;
+    let names: Vec<&str> = remotes.iter().flatten().collect();
+    if let [name] = names[..] {
+        return repo.find_remote(name).ok();
+    }
+    let url = submodule_url(root_repo?, repo)?;
+    let config = repo.config().ok()?;
+    // As configured, the url of the remote itself has the url rewrites applied
+    let name = names.into_iter().find(|name| {
+        config
+            .get_string(&format!("remote.{name}.url"))
+            .ok()
+            .as_ref()
+            == Some(&url)
+    })?;
+    repo.find_remote(name).ok()
+}
+
+/// The url in `.gitmodules` of the submodule of `root_repo` checked out as `repo`.
+fn submodule_url(root_repo: &Repository, repo: &Repository) -> Option<String> {
+    let root_dir = root_repo.workdir()?;
+    let dir = repo.workdir()?.canonicalize().ok()?;
+    root_repo
+        .submodules()
+        .ok()?
+        .iter()
+        .find(|submodule| root_dir.join(submodule.path()).canonicalize().ok() == Some(dir.clone()))
+        .and_then(|submodule| submodule.url().map(str::to_owned))
+}
+
+/// The checked out branch, or the tag or commit of a detached HEAD.
+fn describe_head(repo: &Repository) -> Result<String> {
+    let head = repo.head()?;
+    let commit = head.peel_to_commit()?;
+    let short_id = commit.as_object().short_id()?;
+    let short_id = short_id.as_str().unwrap_or_default();
+    if !repo.head_detached()? {
+        return Ok(format!(
+            "{} ({short_id})",
+            head.shorthand().unwrap_or("HEAD")
+        ));
+    }
+    let mut options = DescribeOptions::new();
+    options.describe_tags().max_candidates_tags(0);
+    let tag = match commit.as_object().describe(&options) {
+        Ok(tag) => Some(tag.format(None)?),
+        Err(_) => None,
+    };
+    Ok(match tag {
+        Some(tag) => format!("{tag} ({short_id})"),
+        None => short_id.to_owned(),
+    })
+}
+
+fn resolve_manifest_path(manifest_path: Option<PathBuf>) -> Result<PathBuf> {
+    let cwd = env::current_dir()?;
+    let manifest_path = match manifest_path {
+        Some(path) => cwd.join(path),
+        None => return Ok(find_root_manifest_for_wd(&cwd)?),
+    };
+    // Mirror other cargo commands in accepting the directory containing the manifest
+    let manifest_path = if manifest_path.is_dir() {
+        manifest_path.join("Cargo.toml")
+    } else {
+        manifest_path
+    };
+    if manifest_path.file_name() != Some("Cargo.toml".as_ref()) {
+        return Err(anyhow!(
+            "the manifest-path must be a path to a Cargo.toml file, got {}",
+            manifest_path.display()
+        )
+        .into());
+    }
+    if !manifest_path.exists() {
+        return Err(anyhow!("manifest path `{}` does not exist", manifest_path.display()).into());
+    }
+    Ok(manifest_path)
+}
+
+/// The manifest of the root of the workspace of `manifest_path`, where the patches are.
+fn root_manifest_path(manifest_path: Option<PathBuf>) -> Result<PathBuf> {
+    let manifest_path = resolve_manifest_path(manifest_path)?;
+    let config = Config::default()?;
+    let workspace = Workspace::new(&manifest_path, &config)?;
+    Ok(workspace.root_manifest().to_owned())
+}
+
+fn manifest_dir(manifest_path: &Path) -> Result<&Path> {
+    let dir = manifest_path
+        .parent()
+        .ok_or_else(|| anyhow!("could not find parent directory of manifest"))?;
+    Ok(dir)
+}
+
+/// Forks and clones the repository of the dependency to `dep_path`, or reuses an earlier local
+/// copy, returning the repository and whether it was cloned by this call.
+pub fn fork_and_clone(
+    resolved: &ResolvedDependency,
+    dir: &Path,
+    dep_path: &Path,
+    args: &Forkdep,
+) -> Result<(Repository, bool)> {
+    let verbosity = args.verbosity();
+    let quiet = verbosity == Verbosity::Quiet;
+    match existing_local_copy(dir, dep_path, args.no_submodule)? {
+        Some(repo) if args.force => {
+            if verbosity >= Verbosity::Normal {
+                println!("{} already exists, re-creating it", dep_path.display());
+            }
+            drop(repo);
+            remove_local_copy(dir, dep_path, args.no_submodule)?;
+        }
+        Some(repo) => {
+            if verbosity >= Verbosity::Normal {
+                println!(
+                    "{} already exists, reusing it, pass --force to re-create it",
+                    dep_path.display()
+                );
+            }
+            if let Some(git_ref) = args.git_ref_for(resolved) {
+                checkout_ref(&repo, &args.remote_name, &git_ref, verbosity)?;
+            }
+            return Ok((repo, false));
+        }
+        None => {
+            let path = dir.join(dep_path);
+            let occupied = path.exists()
+                && fs::read_dir(&path).map_or(true, |mut entries| entries.next().is_some());
+            match (occupied, args.force) {
+                (false, _) => {}
+                (true, true) => {
+                    if verbosity >= Verbosity::Normal {
+                        println!("{} already exists, replacing it", dep_path.display());
+                    }
+                    fs::remove_dir_all(&path)?;
+                }
+                (true, false) => {
+                    return Err(ForkdepError::PathExists {
+                        path: dep_path.to_owned(),
+                        kind: if args.no_submodule {
+                            "git repository"
+                        } else {
+                            "submodule"
+                        },
+                    })
+                }
+            }
+        }
+    }
+    let new_url = if args.no_fork {
+        resolved.repository.clone()
+    } else {
+        fork_repo(&resolved.repository, args, verbosity)?
+    };
+    if verbosity == Verbosity::Verbose {
+        let kind = if args.no_submodule {
+            "clone"
+        } else {
+            "submodule"
+        };
+        println!("cloning {new_url} as a {kind} into {}", dep_path.display());
+    }
+    let token = github_token(args.token.as_deref());
+    let github_host = args.github_host().map(str::to_owned);
+    // Every failed attempt cleans up after itself, so the next one starts from scratch
+    let clone = |shallow: bool| {
+        with_retries(args.retries, "cloning the fork", || {
+            match (args.no_submodule, args.uses_git_cli() || shallow) {
+                (true, false) => {
+                    let repo = RepoBuilder::new()
+                        .fetch_options(fetch_options(quiet, token.clone(), github_host.clone()))
+                        .clone(&new_url, &dir.join(dep_path));
+                    finish_progress(quiet);
+                    repo.map_err(|err| {
+                        undo_failed_clone(err.into(), remove_dir_and_empty_parents(dir, dep_path))
+                    })
+                }
+                (true, true) => {
+                    let dep_dir = dir.join(dep_path);
+                    let dep_dir = path_str(&dep_dir)?;
+                    let mut clone = vec![if quiet { "--quiet" } else { "--progress" }.to_owned()];
+                    clone.extend(args.git_fetch_options(shallow));
+                    if args.single_branch {
+                        clone.push("--single-branch".to_owned());
+                        if let Some(branch) = &args.branch {
+                            clone.push(format!("--branch={branch}"));
+                        }
+                    }
+                    let mut git_args = vec!["clone"];
+                    git_args.extend(clone.iter().map(String::as_str));
+                    git_args.extend([new_url.as_str(), dep_dir]);
+                    run_git(dir, &git_args).map_err(|err| {
+                        undo_failed_clone(err, remove_dir_and_empty_parents(dir, dep_path))
+                    })?;
+                    Ok(Repository::open(dep_dir)?)
+                }
+                (false, git_cli) => {
+                    let (root_repo, submodule_path) = open_root_repo(dir, dep_path)?;
+                    if verbosity == Verbosity::Verbose {
+                        println!("adding submodule {}", submodule_path.display());
+                    }
+                    let mut submodule = root_repo.submodule(&new_url, &submodule_path, false)?;
+                    let repo = if git_cli {
+                        git_cli_fetch(&dir.join(dep_path), args, shallow)
+                    } else {
+                        let mut options = SubmoduleUpdateOptions::new();
+                        options.fetch(fetch_options(quiet, token.clone(), github_host.clone()));
+                        let repo = submodule.clone(Some(&mut options));
+                        finish_progress(quiet);
+                        repo.map_err(Into::into)
+                    };
+                    drop(submodule);
+                    repo.map_err(|err| {
+                        undo_failed_clone(err, remove_submodule(&root_repo, &submodule_path))
+                    })
+                }
+            }
+        })
+    };
+    let repo = match clone(args.shallow) {
+        Err(err) if args.shallow_fallback && is_size_error(&err) => {
+            warn(&format!(
+                "cloning the fork failed, retrying with a shallow clone: {err}"
+            ));
+            clone(true)
+        }
+        result => result,
+    }
+    .map_err(|err| ForkdepError::CloneFailed {
+        url: new_url.clone(),
+        cause: Box::new(err),
+    })?;
+    // A later run would reuse a clone that isn't set up as asked, like one on a mistyped tag
+    match set_up_clone(&repo, resolved, dir, dep_path, &new_url, args) {
+        Ok(()) => Ok((repo, true)),
+        Err(err) => {
+            drop(repo);
+            Err(undo_failed_clone(
+                err,
+                remove_local_copy(dir, dep_path, args.no_submodule),
+            ))
+        }
+    }
+}
+
+/// Checks out what `args` ask for in the fresh clone `repo` and names it and its remote.
+fn set_up_clone(
+    repo: &Repository,
+    resolved: &ResolvedDependency,
+    dir: &Path,
+    dep_path: &Path,
+    new_url: &str,
+    args: &Forkdep,
+) -> Result<()> {
+    let verbosity = args.verbosity();
+    if verbosity == Verbosity::Verbose {
+        // Both libgit2 and git apply the `insteadOf` rules of the git config when cloning, while
+        // `.gitmodules` keeps the url as given
+        let remote = repo.find_remote("origin")?;
+        if let Some(url) = remote.url().filter(|url| *url != new_url) {
+            println!("cloned from {url}, as the git config rewrites {new_url}");
+        }
+    }
+    if let (false, Some(name)) = (args.no_submodule, &args.submodule_name) {
+        rename_submodule(dir, dep_path, name)?;
+    }
+    // Without a version to look for, stay on the default branch
+    match (args.git_ref_for(resolved), resolved.version()) {
+        (Some(git_ref), _) => checkout_ref(repo, "origin", &git_ref, verbosity)?,
+        (None, Some(version)) => checkout_version_tag(repo, &version.to_string(), verbosity)?,
+        (None, None) => {}
+    }
+    if args.remote_name != "origin" {
+        if verbosity == Verbosity::Verbose {
+            println!("renaming the remote origin to {}", args.remote_name);
+        }
+        // Also moves the remote-tracking branches and what the checked out branch tracks
+        let problems = repo.remote_rename("origin", &args.remote_name)?;
+        for refspec in problems.iter().flatten() {
+            warn(&format!(
+                "could not rename the fetch refspec {refspec} of origin, update it by hand"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Commits the manifest in `dir`, `.gitmodules` and the submodules at `dep_paths`.
+fn commit_forks(
+    dir: &Path,
+    dep_paths: &[PathBuf],
+    message: &str,
+    verbosity: Verbosity,
+) -> Result<()> {
+    let (repo, manifest) = open_root_repo(dir, Path::new("Cargo.toml"))?;
+    for dep_path in dep_paths {
+        let (_, submodule_path) = open_root_repo(dir, dep_path)?;
+        repo.find_submodule(path_str(&submodule_path)?)?
+            .add_to_index(true)?;
+    }
+    let mut index = repo.index()?;
+    index.add_path(&manifest)?;
+    let gitmodules = Path::new(".gitmodules");
+    if repo
+        .workdir()
+        .map_or(false, |workdir| workdir.join(gitmodules).exists())
+    {
+        index.add_path(gitmodules)?;
+    }
+    index.write()?;
+    let tree = repo.find_tree(index.write_tree()?)?;
+    let signature = repo.signature()?;
+    // The workspace may not have any commits yet
+    let parent = match repo.head() {
+        Ok(head) => Some(head.peel_to_commit()?),
+        Err(_) => None,
+    };
+    let commit = repo.commit(
+        Some("HEAD"),
+        &signature,
+        &signature,
+        message,
+        &tree,
+        parent.iter().collect::<Vec<_>>().as_slice(),
+    )?;
+    if verbosity >= Verbosity::Normal {
+        let commit = repo.find_commit(commit)?;
+        let short_id = commit.as_object().short_id()?;
+        println!(
+            "committed the patches as {}",
+            short_id.as_str().unwrap_or_default()
+        );
+    }
+    Ok(())
+}
+
+/// Adds the remote `name` pointing at the original repository at `url` to the fork, keeping an
+/// existing remote of that name.
+fn add_upstream_remote(
+    repo: &Repository,
+    name: &str,
+    url: &str,
+    verbosity: Verbosity,
+) -> Result<()> {
+    if let Ok(remote) = repo.find_remote(name) {
+        if remote.url() != Some(url) {
+            warn(&format!(
+                "the fork already has a remote {name} at {}, keeping it",
+                remote.url().unwrap_or("an invalid url")
+            ));
+        }
+        return Ok(());
+    }
+    if verbosity == Verbosity::Verbose {
+        println!("adding remote {name} for {url}");
+    }
+    repo.remote(name, url)?;
+    Ok(())
+}
+
+/// Sets the branch the submodule at `dep_path` tracks in `.gitmodules`.
+fn track_branch(dir: &Path, dep_path: &Path, branch: &str) -> Result<()> {
+    let (mut root_repo, submodule_path) = open_root_repo(dir, dep_path)?;
+    let name = root_repo
+        .submodules()?
+        .iter()
+        .find(|submodule| submodule.path() == submodule_path)
+        .and_then(|submodule| submodule.name().map(str::to_owned))
+        .ok_or_else(|| anyhow!("no submodule found at {}", submodule_path.display()))?;
+    root_repo.submodule_set_branch(&name, branch)?;
+    Ok(())
+}
+
+/// Renames the submodule at `dep_path`, which libgit2 names after its path, to `name`.
+fn rename_submodule(dir: &Path, dep_path: &Path, name: &str) -> Result<()> {
+    if name.is_empty() || name.contains(['"', '\n']) {
+        return Err(anyhow!("{name:?} can't be the name of a submodule").into());
+    }
+    let (root_repo, submodule_path) = open_root_repo(dir, dep_path)?;
+    let old_name = root_repo
+        .submodules()?
+        .iter()
+        .find(|submodule| submodule.path() == submodule_path)
+        .and_then(|submodule| submodule.name().map(str::to_owned))
+        .ok_or_else(|| anyhow!("no submodule found at {}", submodule_path.display()))?;
+    let workdir = root_repo
+        .workdir()
+        .ok_or_else(|| anyhow!("cannot rename a submodule in a bare repository"))?;
+    rename_config_section(&workdir.join(".gitmodules"), &old_name, name)?;
+    rename_config_section(&root_repo.path().join("config"), &old_name, name)?;
+    let mut index = root_repo.index()?;
+    if index.get_path(Path::new(".gitmodules"), 0).is_some() {
+        index.add_path(Path::new(".gitmodules"))?;
+        index.write()?;
+    }
+    Ok(())
+}
+
+/// Removes a local copy made by [`fork_and_clone`].
+fn remove_local_copy(dir: &Path, dep_path: &Path, no_submodule: bool) -> Result<()> {
+    if no_submodule {
+        return remove_dir_and_empty_parents(dir, dep_path);
+    }
+    let (root_repo, submodule_path) = open_root_repo(dir, dep_path)?;
+    remove_submodule(&root_repo, &submodule_path)
+}
+
+/// Runs `operation`, retrying it up to `retries` times with exponential backoff as long as
+/// it fails with a network error.
+fn with_retries<T>(
+    retries: u32,
+    what: &str,
+    mut operation: impl FnMut() -> Result<T>,
+) -> Result<T> {
+    let mut attempt = 0;
+    loop {
+        match operation() {
+            Err(err) if attempt < retries && is_network_error(&err) => {
+                let delay = Duration::from_secs(1 << attempt.min(6));
+                warn(&format!(
+                    "{what} failed, retrying in {}s: {err}",
+                    delay.as_secs()
+                ));
+                std::thread::sleep(delay);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Whether `err` may be down to the size of the repository, like running out of disk space or
+/// time, which a shallow clone might get around.
+fn is_size_error(err: &ForkdepError) -> bool {
+    let message = match err {
+        ForkdepError::Git(err) => err.message().to_lowercase(),
+        ForkdepError::Io(err) if err.kind() == std::io::ErrorKind::TimedOut => return true,
+        ForkdepError::Io(err) => err.to_string().to_lowercase(),
+        _ => return false,
+    };
+    ["no space left", "disk quota", "timed out", "timeout"]
+        .iter()
+        .any(|cause| message.contains(cause))
+}
+
+/// Whether `err` might go away by trying again, unlike authentication failures or missing
+/// repositories.
+fn is_network_error(err: &ForkdepError) -> bool {
+    match err {
+        ForkdepError::Git(err) => match (err.class(), err.code()) {
+            (_, ErrorCode::Auth) => false,
+            (ErrorClass::Net | ErrorClass::Ssl, _) => true,
+            // libgit2 only reports the status code in the message
+            (ErrorClass::Http, _) => err.message().contains("status code: 5"),
+            _ => false,
+        },
+        // Errors reported by the GitHub API itself, like a 404 or a bad token, are final
+        ForkdepError::GitHub(err) => matches!(err, octocrab::Error::Http { .. }),
+        _ => false,
+    }
+}
+
+/// Reports a failure of `cleanup`, which undoes a failed clone, and passes on the error of the clone.
+fn undo_failed_clone(err: ForkdepError, cleanup: Result<()>) -> ForkdepError {
+    if let Err(cleanup_err) = cleanup {
+        warn(&format!(
+            "could not clean up after the failed clone: {cleanup_err}"
+        ));
+    }
+    err
+}
+
+/// Fetch options printing the progress of the transfer, unless `quiet` is set, and
+/// authenticating for private repositories.
+///
+/// SSH urls authenticate through the SSH agent, HTTPS urls with the GitHub `token` for GitHub,
+/// including the enterprise server at `github_host`, and git's credential helpers otherwise.
+fn fetch_options<'cb>(
+    quiet: bool,
+    token: Option<String>,
+    github_host: Option<String>,
+) -> FetchOptions<'cb> {
+    let mut callbacks = credential_callbacks(token, github_host);
+    if !quiet {
+        callbacks.transfer_progress(|progress| {
+            print!(
+                "\rreceived {}/{} objects ({} KiB)",
+                progress.received_objects(),
+                progress.total_objects(),
+                progress.received_bytes() / 1024
+            );
+            let _ = std::io::stdout().flush();
+            true
+        });
+    }
+    let mut options = FetchOptions::new();
+    options.remote_callbacks(callbacks);
+    options
+}
+
+/// Callbacks authenticating like the ones of [`fetch_options`].
+fn credential_callbacks<'cb>(
+    token: Option<String>,
+    github_host: Option<String>,
+) -> RemoteCallbacks<'cb> {
+    let mut callbacks = RemoteCallbacks::new();
+    // libgit2 asks again after a rejected credential, so only try each one once
+    let mut tried_ssh_agent = false;
+    let mut tried_token = false;
+    let mut tried_helper = false;
+    callbacks.credentials(move |url, username, allowed| {
+        if allowed.contains(CredentialType::USERNAME) {
+            return Cred::username(username.unwrap_or("git"));
+        }
+        if allowed.contains(CredentialType::SSH_KEY) && !tried_ssh_agent {
+            tried_ssh_agent = true;
+            return Cred::ssh_key_from_agent(username.unwrap_or("git"));
+        }
+        if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+            if let Some(token) = token.as_deref().filter(|_| !tried_token) {
+                tried_token = true;
+                if let Some(Host::GitHub(_)) = Host::from_url(url, github_host.as_deref()) {
+                    return Cred::userpass_plaintext("x-access-token", token);
+                }
+            }
+            if !tried_helper {
+                tried_helper = true;
+                return Cred::credential_helper(&git2::Config::open_default()?, url, username);
+            }
+        }
+        Err(git2::Error::from_str(&format!(
+            "no more credentials to try for {url}"
+        )))
+    });
+    callbacks
+}
+
+/// Ends the line the transfer progress was printed on.
+fn finish_progress(quiet: bool) {
+    if !quiet {
+        println!();
+    }
+}
+
+/// Fetches the `origin` remote of the freshly set up repository in `dir` with the options of
+/// `args` git2 has no support for, like shallow fetches, and checks out its default branch.
+fn git_cli_fetch(dir: &Path, args: &Forkdep, shallow: bool) -> Result<Repository> {
+    let verbosity = args.verbosity();
+    let quiet = if verbosity == Verbosity::Quiet {
+        "--quiet"
+    } else {
+        "--progress"
+    };
+    let single_branch = match (args.single_branch, &args.branch) {
+        (false, _) => None,
+        (true, Some(branch)) => Some(branch.clone()),
+        (true, None) => Some(remote_default_branch(dir)?),
+    };
+    if let Some(branch) = &single_branch {
+        let refspec = format!("+refs/heads/{branch}:refs/remotes/origin/{branch}");
+        run_git(dir, &["config", "remote.origin.fetch", &refspec])?;
+    }
+    let options = args.git_fetch_options(shallow);
+    let mut fetch = vec!["fetch", quiet];
+    fetch.extend(options.iter().map(String::as_str));
+    fetch.push("origin");
+    run_git(dir, &fetch)?;
+    let branch = match single_branch {
+        Some(branch) => branch,
+        None => {
+            run_git(dir, &["remote", "set-head", "origin", "--auto"])?;
+            default_branch(&Repository::open(dir)?)?
+        }
+    };
+    let repo = Repository::open(dir)?;
+    checkout_ref(&repo, "origin", &GitRef::Branch(branch), verbosity)?;
+    Ok(repo)
+}
+
+/// The default branch of the `origin` remote of the repository in `dir`, asking the remote.
+fn remote_default_branch(dir: &Path) -> Result<String> {
+    let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["ls-remote", "--symref", "origin", "HEAD"])
+        .output()
+        .map_err(|err| anyhow!("could not run git: {}", err))?;
+    if !output.status.success() {
+        return Err(anyhow!("`git ls-remote` failed with {}", output.status).into());
+    }
+    let branch = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|line| {
+            let (target, _) = line.strip_prefix("ref: refs/heads/")?.split_once('\t')?;
+            Some(target.to_owned())
+        })
+        .ok_or_else(|| anyhow!("could not determine the default branch of the fork"))?;
+    Ok(branch)
+}
+
+fn run_git(dir: &Path, args: &[&str]) -> Result<()> {
+    let status = std::process::Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .status()
+        .map_err(|err| anyhow!("could not run git: {}", err))?;
+    if !status.success() {
+        return Err(anyhow!("`git {}` failed with {}", args.join(" "), status).into());
+    }
+    Ok(())
+}
+
+fn path_str(path: &Path) -> Result<&str> {
+    let path_str = path
+        .to_str()
+        .ok_or_else(|| anyhow!("{} is not valid utf-8", path.display()))?;
+    Ok(path_str)
+}
+
+/// Discovers the git repository containing `dir` and makes `path`, relative to `dir`, relative
+/// to the root of that repository instead.
+fn open_root_repo(dir: &Path, path: &Path) -> Result<(Repository, PathBuf)> {
+    let repo = Repository::discover(dir)?;
+    let workdir = repo
+        .workdir()
+        .ok_or_else(|| anyhow!("cannot add a submodule to a bare repository"))?
+        .canonicalize()?;
+    let path = pathdiff::diff_paths(dir.canonicalize()?.join(path), &workdir)
+        .filter(|path| !path.starts_with(".."))
+        .ok_or_else(|| {
+            anyhow!(
+                "{} is outside of the git repository at {}",
+                path.display(),
+                workdir.display()
+            )
+        })?;
+    Ok((repo, path))
+}
+
+/// How a local copy found by [`find_local_copy`] was made.
+enum LocalCopy {
+    /// A submodule of the root repository, at the path relative to its root
+    Submodule(Repository, PathBuf),
+    /// A plain clone, made with `--no-submodule` or outside of a repository
+    Clone,
+}
+
+/// Finds the submodule or clone the patch path `path`, relative to `dir`, points into, which is
+/// a subdirectory of it for a crate in a monorepo. Returns its path relative to `dir` as well.
+///
+/// Only looks inside `dir`, a path leaving it could lead to any repository above it.
+fn find_local_copy(dir: &Path, path: &Path) -> Result<Option<(PathBuf, LocalCopy)>> {
+    let path: PathBuf = path
+        .components()
+        .filter(|component| *component != Component::CurDir)
+        .collect();
+    if !path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Ok(None);
+    }
+    let mut copies = path
+        .ancestors()
+        .take_while(|copy| !copy.as_os_str().is_empty());
+    if let Ok((root_repo, repo_path)) = open_root_repo(dir, &path) {
+        let submodules: Vec<PathBuf> = root_repo
+            .submodules()?
+            .iter()
+            .map(|submodule| submodule.path().to_owned())
+            .collect();
+        // Both walk up one directory at a time, only their roots differ
+        let found = repo_path
+            .ancestors()
+            .zip(copies.clone())
+            .find(|(submodule, _)| submodules.iter().any(|path| path == submodule));
+        if let Some((submodule, copy)) = found {
+            let submodule = submodule.to_owned();
+            return Ok(Some((
+                copy.to_owned(),
+                LocalCopy::Submodule(root_repo, submodule),
+            )));
+        }
+    }
+    Ok(copies
+        .find(|copy| dir.join(copy).join(".git").exists())
+        .map(|copy| (copy.to_owned(), LocalCopy::Clone)))
+}
+
+/// Whether any path patch of `manifest` points into `copy`.
+fn patches_into(manifest: &Document, copy: &Path) -> bool {
+    path_patches(manifest)
+        .iter()
+        .any(|(_, path)| Path::new(path).starts_with(copy))
+}
+
+/// Opens the local copy at `dep_path` left behind by an earlier run, if there is one.
+fn existing_local_copy(
+    dir: &Path,
+    dep_path: &Path,
+    no_submodule: bool,
+) -> Result<Option<Repository>> {
+    if no_submodule {
+        return Ok(Repository::open(dir.join(dep_path)).ok());
+    }
+    let (root_repo, submodule_path) = open_root_repo(dir, dep_path)?;
+    let submodule = root_repo
+        .submodules()?
+        .into_iter()
+        .find(|submodule| submodule.path() == submodule_path);
+    match submodule {
+        Some(submodule) => Ok(Some(submodule.open()?)),
+        None => Ok(None),
+    }
+}
+
+/// Checks out the tag of the resolved version so that the fork matches the source Cargo uses,
+/// or the default branch of the fork without one.
+fn checkout_version_tag(repo: &Repository, version: &str, verbosity: Verbosity) -> Result<()> {
+    for tag in [format!("v{version}"), version.to_owned()] {
+        if repo.revparse_single(&format!("refs/tags/{tag}")).is_ok() {
+            return checkout_ref(repo, "origin", &GitRef::Tag(tag), verbosity);
+        }
+    }
+    let branch = default_branch(repo)?;
+    warn(&format!(
+        "no tag for version {version} found, staying on the default branch {branch}"
+    ));
+    checkout_ref(repo, "origin", &GitRef::Branch(branch), verbosity)
+}
+
+/// The default branch of the `origin` remote of `repo`, which need not be `master` or `main`.
+fn default_branch(repo: &Repository) -> Result<String> {
+    // Set by the clone, but not by every way of creating a repository
+    if let Some(branch) = repo
+        .find_reference("refs/remotes/origin/HEAD")
+        .ok()
+        .and_then(|head| head.symbolic_target().map(str::to_owned))
+        .and_then(|target| {
+            target
+                .strip_prefix("refs/remotes/origin/")
+                .map(str::to_owned)
+        })
+    {
+        return Ok(branch);
+    }
+    let mut remote = repo.find_remote("origin")?;
+    let callbacks = credential_callbacks(github_token(None), None);
+    remote.connect_auth(Direction::Fetch, Some(callbacks), None)?;
+    let head = remote.default_branch()?;
+    let branch = head
+        .as_str()
+        .and_then(|head| head.strip_prefix("refs/heads/"))
+        .ok_or_else(|| anyhow!("could not determine the default branch of the fork"))?
+        .to_owned();
+    Ok(branch)
+}
+
+/// Checks out `git_ref`, looking branches up on the remote `remote`.
+fn checkout_ref(
+    repo: &Repository,
+    remote: &str,
+    git_ref: &GitRef,
+    verbosity: Verbosity,
+) -> Result<()> {
+    if verbosity == Verbosity::Verbose {
+        match git_ref {
+            GitRef::Branch(branch) => println!("checking out branch {branch}"),
+            GitRef::Tag(tag) => println!("checking out tag {tag}"),
+            GitRef::Rev(rev) => println!("checking out rev {rev}"),
+        }
+    }
+    match git_ref {
+        GitRef::Branch(branch) => {
+            let commit = repo
+                .revparse_single(&format!("{remote}/{branch}"))
+                .map_err(|_| anyhow!("could not find branch {} in the fork", branch))?
+                .peel_to_commit()?;
+            // git refuses to force update the checked out branch, which a fresh clone of the
+            // default branch already is
+            let head = repo.head().ok();
+            if head.as_ref().and_then(|head| head.shorthand()) != Some(branch.as_str()) {
+                repo.branch(branch, &commit, true)?;
+            }
+            repo.checkout_tree(commit.as_object(), None)?;
+            repo.set_head(&format!("refs/heads/{branch}"))?;
+        }
+        GitRef::Tag(tag) => detach_head(repo, &format!("refs/tags/{tag}"))?,
+        GitRef::Rev(rev) => detach_head(repo, rev)?,
+    }
+    Ok(())
+}
+
+/// Creates `branch` at the checked out commit and switches to it.
+fn create_branch(repo: &Repository, branch: &str, force: bool, verbosity: Verbosity) -> Result<()> {
+    if !force && repo.find_branch(branch, BranchType::Local).is_ok() {
+        return Err(anyhow!(
+            "branch {} already exists in the fork, pass --force to reset it",
+            branch
+        )
+        .into());
+    }
+    let head = repo.head()?;
+    if head.is_branch() && head.shorthand() == Some(branch) {
+        // Resetting the checked out branch to its own commit changes nothing
+        return Ok(());
+    }
+    let commit = head.peel_to_commit()?;
+    repo.branch(branch, &commit, force)?;
+    repo.set_head(&format!("refs/heads/{branch}"))?;
+    if verbosity == Verbosity::Verbose {
+        println!("created branch {branch}");
+    }
+    Ok(())
+}
+
+fn detach_head(repo: &Repository, spec: &str) -> Result<()> {
+    let commit = repo
+        .revparse_single(spec)
+        .map_err(|_| anyhow!("could not find {} in the fork", spec))?
+        .peel_to_commit()?;
+    repo.checkout_tree(commit.as_object(), None)?;
+    repo.set_head_detached(commit.id())?;
+    Ok(())
+}
+
+fn fork_repo(url: &str, args: &Forkdep, verbosity: Verbosity) -> Result<String> {
+    let ssh = args.use_ssh(url);
+    let host = Host::from_url(url, args.github_host());
+    if let Some(host @ Host::GitHub(_)) = &host {
+        if let Some(token) = github_token(args.token.as_deref()) {
+            if verbosity == Verbosity::Verbose {
+                println!("forking {url} through the GitHub API");
+            }
+            match with_retries(args.retries, "forking through the GitHub API", || {
+                fork_with_api(url, host, &token, ssh, verbosity)
+            }) {
+                Ok(fork_url) => return Ok(fork_url),
+                Err(err) => warn(&format!(
+                    "could not fork {url} through the GitHub API: {err}"
+                )),
+            }
+        }
+    }
+    if let (Some(host), Some(owner)) = (&host, args.owner.as_deref()) {
+        let fork_url = fork_url(host, url, owner, ssh)?;
+        if verbosity == Verbosity::Verbose {
+            println!("using the fork {fork_url} of {owner}");
+        }
+        return Ok(fork_url);
+    }
+    if args.no_browser || is_headless() || open(url).is_err() {
+        println!("fork the repository at {}", url);
+    }
+    let host = match host {
+        Some(host) => host,
+        None => {
+            // Fork urls of unknown hosts can't be guessed, so ask for all of it
+            return prompt(
+                "Enter the url of the fork: ",
+                "the url of the fork",
+                "clone the fork by hand and patch it in with a path",
+            );
+        }
+    };
+    let owner = prompt(
+        "Enter the name of the owner of the fork: ",
+        "the owner of the fork",
+        "pass it with --owner",
+    )?;
+    fork_url(&host, url, &owner, ssh)
+}
+
+/// The `--prompt-timeout` of the run in seconds, or 0 to wait for answers as long as it takes.
+static PROMPT_TIMEOUT: AtomicU64 = AtomicU64::new(0);
+
+/// Asks `question` and returns the trimmed answer. The answer can be piped in, but when stdin
+/// is not a terminal and has run out this fails with `hint` instead of going on without one.
+fn prompt(question: &str, what: &'static str, hint: &'static str) -> Result<String> {
+    let timeout = match PROMPT_TIMEOUT.load(Ordering::Relaxed) {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    // On stderr, like the messages leading up to it, so that stdout stays parseable
+    eprintln!("{question}");
+    match read_answer(what, timeout)? {
+        Some(answer) => Ok(answer.trim().to_owned()),
+        None if atty::isnt(atty::Stream::Stdin) => Err(ForkdepError::NotInteractive { what, hint }),
+        None => Ok(String::new()),
+    }
+}
+
+/// Reads the line answering for `what` from stdin, or `None` at the end of the input.
+fn read_answer(what: &'static str, timeout: Option<Duration>) -> Result<Option<String>> {
+    let read = || -> Result<Option<String>> {
+        let mut answer = String::new();
+        let read = std::io::stdin().read_line(&mut answer)?;
+        Ok((read > 0).then_some(answer))
+    };
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return read(),
+    };
+    // Reading stdin can't be interrupted, so leave the thread blocked when it times out
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = sender.send(read());
+    });
+    receiver
+        .recv_timeout(timeout)
+        .unwrap_or(Err(ForkdepError::PromptTimeout { what, timeout }))
+}
+
+/// Whether there is no browser to open, either because there is no display or because this
+/// runs in CI.
+fn is_headless() -> bool {
+    if env::var_os("CI").is_some() {
+        return true;
+    }
+    // Everywhere else the browser is opened without a display server
+    cfg!(all(unix, not(target_os = "macos")))
+        && env::var_os("DISPLAY").is_none()
+        && env::var_os("WAYLAND_DISPLAY").is_none()
+}
+
+fn fork_url(host: &Host, url: &str, owner: &str, ssh: bool) -> Result<String> {
+    let repo = repo_path(url)
+        .and_then(|path| path.split('/').next_back())
+        .filter(|repo| !repo.is_empty())
+        .ok_or_else(|| anyhow!("could not parse url {}", url))?;
+    let domain = host.domain();
+    Ok(if ssh {
+        format!("git@{domain}:{owner}/{repo}.git")
+    } else {
+        format!("https://{domain}/{owner}/{repo}.git")
+    })
+}
+
+fn is_ssh_url(url: &str) -> bool {
+    match url.split_once("://") {
+        Some((scheme, _)) => scheme == "ssh" || scheme == "git+ssh",
+        None => split_git_url(url).is_some(),
+    }
+}
+
+/// Splits a git url, either `scheme://[user@]host[:port]/path` or scp-like `user@host:path`,
+/// into its host and path.
+fn split_git_url(url: &str) -> Option<(&str, &str)> {
+    let (authority, path) = match url.split_once("://") {
+        Some((_, rest)) => rest.split_once('/').unwrap_or((rest, "")),
+        None => url.split_once(':')?,
+    };
+    let domain = authority.rsplit('@').next()?.split(':').next()?;
+    Some((domain, path))
+}
+
+/// The path of the repository in `url`, without surrounding slashes or a `.git` suffix.
+fn repo_path(url: &str) -> Option<&str> {
+    let (_, path) = split_git_url(url)?;
+    Some(path.trim_matches('/').trim_end_matches(".git"))
+}
+
+/// Git hosting services whose fork urls can be derived from the owner of the fork.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Host {
+    /// github.com or the GitHub Enterprise server at the domain.
+    GitHub(String),
+    GitLab,
+    Bitbucket,
+}
+
+impl Host {
+    /// The host of `url`, treating `github_host` and domains starting with `github.` as GitHub
+    /// Enterprise servers.
+    fn from_url(url: &str, github_host: Option<&str>) -> Option<Host> {
+        let (domain, _) = split_git_url(url)?;
+        match domain.trim_start_matches("www.") {
+            "github.com" => Some(Host::GitHub("github.com".to_owned())),
+            "gitlab.com" => Some(Host::GitLab),
+            "bitbucket.org" => Some(Host::Bitbucket),
+            domain if Some(domain) == github_host || domain.starts_with("github.") => {
+                Some(Host::GitHub(domain.to_owned()))
+            }
+            _ => None,
+        }
+    }
+
+    fn domain(&self) -> &str {
+        match self {
+            Host::GitHub(domain) => domain,
+            Host::GitLab => "gitlab.com",
+            Host::Bitbucket => "bitbucket.org",
+        }
+    }
+
+    /// The url of the REST API of a GitHub Enterprise server, `None` for github.com and the
+    /// other hosts.
+    fn enterprise_api_url(&self) -> Option<String> {
+        match self {
+            Host::GitHub(domain) if domain != "github.com" => {
+                Some(format!("https://{domain}/api/v3/"))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Resolves the GitHub personal access token at runtime, preferring an explicit token, then
+/// the environment, then the keychain and finally the token file in the user config directory.
+fn github_token(explicit: Option<&str>) -> Option<String> {
+    explicit
+        .map(str::to_owned)
+        .or_else(|| {
+            ["CARGO_FORKDEP_TOKEN", "GITHUB_TOKEN"]
+                .iter()
+                .find_map(|var| env::var(var).ok().filter(|token| !token.trim().is_empty()))
+        })
+        .or_else(keyring_token)
+        .or_else(|| fs::read_to_string(token_file()?).ok())
+        .map(|token| token.trim().to_owned())
+        .filter(|token| !token.is_empty())
+}
+
+fn token_file() -> Option<PathBuf> {
+    Some(dirs::config_dir()?.join("cargo-forkdep").join("token"))
+}
+
+#[cfg(feature = "keyring")]
+fn keyring_entry() -> keyring::Entry {
+    keyring::Entry::new("cargo-forkdep", "github")
+}
+
+#[cfg(feature = "keyring")]
+fn keyring_token() -> Option<String> {
+    keyring_entry().get_password().ok()
+}
+
+#[cfg(not(feature = "keyring"))]
+fn keyring_token() -> Option<String> {
+    None
+}
+
+/// Prints completions for `cargo-forkdep`, which leave those of cargo itself alone.
+fn completions(args: Completions) {
+    let mut command = Forkdep::augment_args(clap::Command::new("cargo-forkdep"));
+    clap_complete::generate(
+        args.shell,
+        &mut command,
+        "cargo-forkdep",
+        &mut std::io::stdout(),
+    );
+}
+
+/// The outcome of one of the checks of `cargo forkdep doctor`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Check {
+    Pass,
+    /// Forking works, but not as smoothly.
+    Warn,
+    Fail,
+}
+
+/// Prints a checklist of what forking needs, with hints for the checks that don't pass.
+fn doctor(args: Doctor, verbosity: Verbosity, offline: bool) -> Result<()> {
+    let config = cargo_config(verbosity, offline)?;
+    let mut failures = 0;
+    let mut report = |check: Check, message: &str, hint: &str| {
+        let (label, color) = match check {
+            Check::Pass => ("ok", Color::Green),
+            Check::Warn => ("warn", Color::Yellow),
+            Check::Fail => {
+                failures += 1;
+                ("fail", Color::Red)
+            }
+        };
+        print_colored(
+            false,
+            &[
+                ("[", None),
+                (&format!("{label:^4}"), Some(color)),
+                ("] ", None),
+                (message, None),
+            ],
+        );
+        if check != Check::Pass {
+            println!("       {hint}");
+        }
+    };
+    let workspace = resolve_manifest_path(args.manifest_path)
+        .and_then(|manifest_path| Ok(Workspace::new(&manifest_path, &config)?));
+    let workspace = match workspace {
+        Ok(workspace) => workspace,
+        Err(err) => {
+            report(
+                Check::Fail,
+                &format!("could not load the workspace: {err}"),
+                "run this in a cargo project or point --manifest-path at its Cargo.toml",
+            );
+            return Err(anyhow!("the workspace could not be loaded").into());
+        }
+    };
+    let kind = if workspace.is_virtual() {
+        format!(
+            "a virtual workspace with {} members",
+            workspace.members().count()
+        )
+    } else {
+        "a package".to_owned()
+    };
+    report(
+        Check::Pass,
+        &format!("{} is {kind}", workspace.root_manifest().display()),
+        "",
+    );
+    match Repository::discover(workspace.root()) {
+        Ok(repo) => report(
+            Check::Pass,
+            &format!(
+                "the workspace is in the git repository at {}",
+                repo.workdir().unwrap_or_else(|| repo.path()).display()
+            ),
+            "",
+        ),
+        Err(_) => report(
+            Check::Fail,
+            "the workspace is not in a git repository, so forks can't be added as submodules",
+            "run `git init` in the workspace root, or fork with --no-submodule",
+        ),
+    }
+    match load_pkg_lockfile(&workspace) {
+        Ok(Some(_)) => report(Check::Pass, "Cargo.lock can be loaded", ""),
+        Ok(None) => report(
+            Check::Warn,
+            "there is no Cargo.lock yet",
+            "it is generated when forking, which needs network access",
+        ),
+        Err(err) => report(
+            Check::Fail,
+            &format!("Cargo.lock can't be loaded: {err}"),
+            "run `cargo update` to regenerate it",
+        ),
+    }
+    match github_token(None) {
+        Some(_) => report(Check::Pass, "a GitHub token is available", ""),
+        None => report(
+            Check::Warn,
+            "no GitHub token found, so repositories have to be forked by hand",
+            "store one with `cargo forkdep login` or set CARGO_FORKDEP_TOKEN",
+        ),
+    }
+    let git = std::process::Command::new("git").arg("--version").output();
+    match git {
+        Ok(output) if output.status.success() => {
+            report(Check::Pass, "the git command line tool is available", "")
+        }
+        _ => report(
+            Check::Warn,
+            "the git command line tool was not found",
+            "--shallow, --single-branch, --filter and syncing such forks need it on the PATH",
+        ),
+    }
+    if failures > 0 {
+        return Err(anyhow!("{failures} of the checks failed").into());
+    }
+    Ok(())
+}
+
+fn login(args: Login) -> Result<()> {
+    let token = match args.token {
+        Some(token) => token,
+        None => prompt(
+            "Enter a GitHub personal access token with the `public_repo` scope: ",
+            "a token",
+            "pass it with --token",
+        )?,
+    };
+    let token = token.trim();
+    if token.is_empty() {
+        return Err(anyhow!("no token given").into());
+    }
+    let location = store_token(token)?;
+    println!("stored the token in {location}");
+    Ok(())
+}
+
+/// Stores `token` where [`github_token`] finds it, returning a description of the location.
+#[cfg(feature = "keyring")]
+fn store_token(token: &str) -> Result<String> {
+    keyring_entry()
+        .set_password(token)
+        .map_err(|err| anyhow!("could not store the token in the keychain: {}", err))?;
+    Ok("the keychain".to_owned())
+}
+
+/// Stores `token` where [`github_token`] finds it, returning a description of the location.
+#[cfg(not(feature = "keyring"))]
+fn store_token(token: &str) -> Result<String> {
+    let file = token_file().ok_or_else(|| anyhow!("could not find the user config directory"))?;
+    if let Some(dir) = file.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    // Keep the token private to the user
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(&file)?.write_all(token.as_bytes())?;
+    Ok(file.display().to_string())
+}
+
+fn parse_github_url(url: &str) -> Result<(String, String)> {
+    let path = repo_path(url).unwrap_or_default();
+    match path.split_once('/') {
+        Some((owner, repo)) if !owner.is_empty() && !repo.is_empty() && !repo.contains('/') => {
+            Ok((owner.to_owned(), repo.to_owned()))
+        }
+        _ => Err(anyhow!("could not parse owner and repository from {}", url).into()),
+    }
+}
+
+/// Forks the repository at `url` on the GitHub `host` for the owner of `token`, reusing an
+/// existing fork of theirs.
+fn fork_with_api(
+    url: &str,
+    host: &Host,
+    token: &str,
+    ssh: bool,
+    verbosity: Verbosity,
+) -> Result<String> {
+    const FORK_POLL_ATTEMPTS: u32 = 30;
+    let (owner, repo) = parse_github_url(url)?;
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(async {
+        let mut builder = Octocrab::builder().personal_token(token.to_owned());
+        if let Some(api_url) = host.enterprise_api_url() {
+            builder = builder.base_url(api_url)?;
+        }
+        let octocrab = builder.build()?;
+        let user = octocrab.current().user().await?.login;
+        if let Ok(existing) = octocrab.repos(&user, &repo).get().await {
+            let upstream = format!("{owner}/{repo}");
+            // A fork of a fork of the upstream has the upstream as its source rather than parent
+            let forked_from_upstream = [&existing.parent, &existing.source]
+                .into_iter()
+                .flatten()
+                .filter_map(|forked| forked.full_name.as_deref())
+                .any(|name| name.eq_ignore_ascii_case(&upstream));
+            if !forked_from_upstream {
+                return Err(ForkdepError::ForkNameTaken {
+                    repository: format!("{user}/{repo}"),
+                    upstream,
+                });
+            }
+            if verbosity >= Verbosity::Normal {
+                println!("reusing the existing fork {user}/{}", existing.name);
+            }
+            return clone_url(existing, ssh);
+        }
+        let fork = octocrab.repos(&owner, &repo).create_fork().send().await?;
+        let fork_owner = fork
+            .owner
+            .as_ref()
+            .map(|owner| owner.login.clone())
+            .ok_or_else(|| anyhow!("GitHub did not report the owner of the fork"))?;
+        let default_branch = match fork.default_branch.clone() {
+            Some(branch) => branch,
+            // The fork starts out with the default branch of its parent
+            None => octocrab
+                .repos(&owner, &repo)
+                .get()
+                .await?
+                .default_branch
+                .ok_or_else(|| {
+                    anyhow!("GitHub did not report the default branch of {owner}/{repo}")
+                })?,
+        };
+        // Forking happens asynchronously, so wait until the git objects are available
+        let fork_handler = octocrab.repos(&fork_owner, &fork.name);
+        let mut attempts = 0;
+        while fork_handler
+            .get_ref(&Reference::Branch(default_branch.clone()))
+            .await
+            .is_err()
+        {
+            attempts += 1;
+            if attempts == FORK_POLL_ATTEMPTS {
+                return Err(anyhow!("timed out waiting for the fork to become ready").into());
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        if verbosity >= Verbosity::Normal {
+            println!("created the fork {fork_owner}/{}", fork.name);
+        }
+        clone_url(fork, ssh)
+    })
+}
+
+fn clone_url(fork: octocrab::models::Repository, ssh: bool) -> Result<String> {
+    let clone_url = if ssh {
+        fork.ssh_url
+    } else {
+        fork.clone_url.map(|url| url.to_string())
+    };
+    Ok(clone_url.ok_or_else(|| anyhow!("GitHub did not report a clone url for the fork"))?)
+}
+
+/// The `[patch]` table overriding `source_id`, keyed by url for git repositories and
+/// alternative registries.
+fn patch_table(source_id: SourceId) -> String {
+    if source_id.is_default_registry() {
+        "crates-io".to_owned()
+    } else {
+        source_id.url().to_string()
+    }
+}
+
+/// Whether a patch in `[patch.<table>]` applies to packages from `source`. The table can name
+/// a registry or give the url of the source.
+fn table_matches_source(config: &Config, table: &str, source: SourceId) -> bool {
+    if table == patch_table(source) {
+        return true;
+    }
+    let table_url = if table.contains("://") {
+        table
+            .into_url()
+            .and_then(|url| CanonicalUrl::new(&url))
+            .ok()
+    } else {
+        registry_source(config, table)
+            .ok()
+            .map(|registry| registry.canonical_url().clone())
+    };
+    table_url.as_ref() == Some(source.canonical_url())
+}
+
+fn patch_header(table: &str) -> String {
+    format!("[patch.{}]", Key::new(table))
+}
+
+/// Points the patch of `dep` in `[patch.<table>]` at `path`, returning whether it was already patched.
+///
+/// `features` and `default-features` are only written when asked for, to keep the patch minimal.
+/// `comment` goes at the end of the line of the patch, replacing the one there.
+pub fn apply_patch(
+    manifest: &mut Document,
+    table: &str,
+    path: &Path,
+    dep: &str,
+    features: &[String],
+    no_default_features: bool,
+    comment: Option<&str>,
+) -> Result<bool> {
+    let had_patch = manifest.contains_key("patch");
+    let patch = manifest
+        .as_table_mut()
+        .entry("patch")
+        .or_insert_with(|| Item::Table(Table::new()))
+        .as_table_mut()
+        .ok_or_else(|| anyhow!("patch is not a Table"))?;
+    // Only hide the `[patch]` header we made, a written one may carry comments
+    if !had_patch {
+        patch.set_implicit(true);
+    }
+    let source = patch
+        .entry(table)
+        .or_insert_with(|| Item::Table(Table::new()))
+        .as_table_mut()
+        .ok_or_else(|| anyhow!("{} is not a Table", table))?;
+    let existed = source.contains_key(dep);
+    let dependency = source
+        .entry(dep)
+        .or_insert_with(|| Item::Value(InlineTable::new().into()))
+        .as_table_like_mut()
+        .ok_or_else(|| anyhow!("the patch of {} is not a table", dep))?;
+    // An existing patch may point somewhere else, which can't be combined with a path
+    for key in ["git", "branch", "tag", "rev", "registry"] {
+        dependency.remove(key);
+    }
+    // Always use forward slashes so the manifest is the same on every platform
+    let path = path
+        .to_str()
+        .ok_or_else(|| anyhow!("Could not write patch path to file"))?
+        .replace(std::path::MAIN_SEPARATOR, "/");
+    match dependency.get_mut("path").and_then(Item::as_value_mut) {
+        // Replace only the value, keeping the whitespace and comments around it
+        Some(existing) => {
+            let decor = existing.decor().clone();
+            *existing = path.into();
+            *existing.decor_mut() = decor;
+        }
+        None => {
+            dependency.insert("path", toml_edit::value(path));
+        }
+    }
+    let features: Vec<&str> = features
+        .iter()
+        .flat_map(|features| features.split_whitespace())
+        .collect();
+    if !features.is_empty() {
+        dependency.insert("features", toml_edit::value(Array::from_iter(features)));
+    }
+    if no_default_features {
+        dependency.insert("default-features", toml_edit::value(false));
+    }
+    if let Some(comment) = comment {
+        let suffix = format!(" # {comment}");
+        match source.get_mut(dep) {
+            Some(Item::Value(value)) => value.decor_mut().set_suffix(suffix),
+            // After the header of a `[patch.<table>.<dep>]` table
+            Some(Item::Table(table)) => table.decor_mut().set_suffix(suffix),
+            _ => {}
+        }
+    }
+    Ok(existed)
+}
+
+/// The comment at the end of the patch of `dep`, without the `#`.
+fn patch_comment(manifest: &Document, dep: &str) -> Option<String> {
+    let table = existing_patch_table(manifest, dep)?;
+    let suffix = match manifest.get("patch")?.get(&table)?.get(dep)? {
+        Item::Value(value) => value.decor().suffix(),
+        Item::Table(table) => table.decor().suffix(),
+        _ => None,
+    }?;
+    Some(suffix.trim().trim_start_matches('#').trim().to_owned())
+}
+
+/// The `[patch]` table already holding a patch of `dep`, if any.
+fn existing_patch_table(manifest: &Document, dep: &str) -> Option<String> {
+    manifest
+        .get("patch")
+        .and_then(Item::as_table_like)?
+        .iter()
+        .find(|(_, source)| source.get(dep).is_some())
+        .map(|(table, _)| table.to_owned())
+}
+
+/// Removes the patch of `dep` from whichever source table holds it and returns the patch path.
+fn remove_patch(manifest: &mut Document, dep: &str) -> Result<PathBuf> {
+    let not_forked = || ForkdepError::NotForked {
+        dependency: dep.to_owned(),
+    };
+    let table = existing_patch_table(manifest, dep).ok_or_else(not_forked)?;
+    let patch = manifest
+        .get_mut("patch")
+        .and_then(Item::as_table_like_mut)
+        .ok_or_else(not_forked)?;
+    let source = patch
+        .get_mut(&table)
+        .and_then(Item::as_table_like_mut)
+        .ok_or_else(|| anyhow!("{} is not a Table", table))?;
+    let entry = source.remove(dep).ok_or_else(not_forked)?;
+    let path = entry
+        .get("path")
+        .and_then(Item::as_str)
+        .ok_or_else(|| anyhow!("the patch of {} is not a path patch", dep))?
+        .into();
+    if source.is_empty() {
+        patch.remove(&table);
+    }
+    if patch.is_empty() {
+        manifest.remove("patch");
+    }
+    Ok(path)
+}
+
+/// Deinitializes the submodule at `path` and removes it from `.gitmodules`, the index and disk.
+fn remove_submodule(repo: &Repository, path: &Path) -> Result<()> {
+    let workdir = repo
+        .workdir()
+        .ok_or_else(|| anyhow!("cannot remove a submodule from a bare repository"))?;
+    let submodule = repo
+        .submodules()?
+        .into_iter()
+        .find(|submodule| submodule.path() == path)
+        .ok_or_else(|| anyhow!("no submodule found at {}", path.display()))?;
+    let name = submodule
+        .name()
+        .ok_or_else(|| anyhow!("submodule name is not valid utf-8"))?
+        .to_owned();
+    drop(submodule);
+    let gitmodules = workdir.join(".gitmodules");
+    remove_config_section(&gitmodules, &name)?;
+    remove_config_section(&repo.path().join("config"), &name)?;
+    let mut index = repo.index()?;
+    if index.get_path(path, 0).is_some() {
+        index.remove_path(path)?;
+    }
+    if fs::read_to_string(&gitmodules)?.trim().is_empty() {
+        fs::remove_file(&gitmodules)?;
+        if index.get_path(Path::new(".gitmodules"), 0).is_some() {
+            index.remove_path(Path::new(".gitmodules"))?;
+        }
+    } else if index.get_path(Path::new(".gitmodules"), 0).is_some() {
+        index.add_path(Path::new(".gitmodules"))?;
+    }
+    index.write()?;
+    remove_dir_and_empty_parents(&repo.path().join("modules"), Path::new(&name))?;
+    remove_dir_and_empty_parents(workdir, path)?;
+    Ok(())
+}
+
+/// Removes the `[submodule "<name>"]` section from a git config file.
+///
+/// libgit2 can only remove individual keys, which leaves an empty section header behind.
+fn remove_config_section(config: &Path, name: &str) -> Result<()> {
+    let header = format!("[submodule \"{name}\"]");
+    let data = fs::read_to_string(config)?;
+    let mut in_section = false;
+    let mut kept = String::with_capacity(data.len());
+    for line in data.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            in_section = trimmed == header;
+        }
+        if !in_section {
+            kept.push_str(line);
+            kept.push('\n');
+        }
+    }
+    fs::write(config, kept)?;
+    Ok(())
+}
+
+/// Renames the `[submodule "<old>"]` section of a git config file to `new`.
+fn rename_config_section(config: &Path, old: &str, new: &str) -> Result<()> {
+    let header = format!("[submodule \"{old}\"]");
+    let data = fs::read_to_string(config)?;
+    let renamed: Vec<String> = data
+        .lines()
+        .map(|line| {
+            if line.trim() == header {
+                format!("[submodule \"{new}\"]")
+            } else {
+                line.to_owned()
+            }
+        })
+        .collect();
+    fs::write(config, renamed.join("\n") + "\n")?;
+    Ok(())
+}
+
+/// Removes `base/path` and then every parent directory below `base` that was left empty.
+fn remove_dir_and_empty_parents(base: &Path, path: &Path) -> Result<()> {
+    let dir = base.join(path);
+    if dir.exists() {
+        fs::remove_dir_all(&dir)?;
+    }
+    let mut parent = path.parent();
+    while let Some(dir) = parent.filter(|dir| !dir.as_os_str().is_empty()) {
+        if fs::remove_dir(base.join(dir)).is_err() {
+            break;
+        }
+        parent = dir.parent();
+    }
+    Ok(())
+}
+
+fn dependency_not_found(
+    workspace: &Workspace,
+    lockfile: &Resolve,
+    dependency: &str,
+) -> ForkdepError {
+    let members: Vec<String> = workspace.members().map(|m| m.name().to_string()).collect();
+    let mut names: Vec<&str> = lockfile
+        .iter()
+        .filter(|id| !members.iter().any(|member| id.name() == member.as_str()))
+        .map(|id| id.name().as_str())
+        .collect();
+    names.sort_unstable();
+    names.dedup();
+    let hint = match closest(dependency, names.iter(), |name| name) {
+        Some(name) => format!("did you mean `{name}`?"),
+        None if names.is_empty() => "the workspace has no dependencies".to_owned(),
+        None if names.len() <= 10 => format!("the dependencies are {}", names.join(", ")),
+        None => "see `cargo forkdep list` for the dependencies".to_owned(),
+    };
+    // A virtual manifest has no dependencies of its own, so point at the members that were searched
+    let searched = if workspace.is_virtual() {
+        format!(
+            " in the members {} of the workspace at {}",
+            members.join(", "),
+            workspace.root().display()
+        )
+    } else {
+        String::new()
+    };
+    ForkdepError::NotFound {
+        dependency: dependency.to_owned(),
+        searched,
+        hint,
+    }
+}
+
+fn list_versions(ids: &[PackageId]) -> String {
+    let versions: Vec<String> = ids.iter().map(|id| id.version().to_string()).collect();
+    versions.join(", ")
+}
+
+/// Asks which of `ids`, packages of `dependency` from different sources, to fork.
+fn choose_source(dependency: &str, ids: &[PackageId]) -> Result<PackageId> {
+    let choices: Vec<String> = ids
+        .iter()
+        .map(|id| format!("{} from {}", id.version(), patch_table(id.source_id())))
+        .collect();
+    println!("{dependency} comes from several sources:");
+    for (number, choice) in choices.iter().enumerate() {
+        println!("  {}) {choice}", number + 1);
+    }
+    let answer = prompt(
+        "Enter the number of the one to fork: ",
+        "the source to fork from",
+        "choose one with --source",
+    )?;
+    answer
+        .parse::<usize>()
+        .ok()
+        .and_then(|number| ids.get(number.checked_sub(1)?))
+        .copied()
+        .ok_or_else(|| ForkdepError::MultipleSources {
+            dependency: dependency.to_owned(),
+            sources: choices.join(", "),
+        })
+}
+
+fn prompt_repository(dependency: &str, tried: &str) -> Result<String> {
+    eprintln!("found no repository to clone {dependency} from in {tried}");
+    let url = prompt(
+        &format!("Enter the url of the repository of {dependency}: "),
+        "the url of the repository",
+        "fork it with --url",
+    )?;
+    if url.is_empty() {
+        return Err(ForkdepError::NoRepository {
+            dependency: dependency.to_owned(),
+            tried: tried.to_owned(),
+        });
+    }
+    Ok(url)
+}
+
+/// The repository the package of `manifest` was published from. Falls back to the `repository`
+/// of the `others` versions of the package in the lockfile, then to what crates.io lists and to
+/// the homepage, before asking for it.
+fn published_repository(
+    workspace: &Workspace,
+    manifest: &Manifest,
+    others: &[PackageId],
+    verbosity: Verbosity,
+) -> Result<String> {
+    let id = manifest.package_id();
+    let dependency = id.name();
+    let metadata = manifest.metadata();
+    if let Some(repository) = &metadata.repository {
+        return Ok(repository.clone());
+    }
+    let mut tried = vec!["the `repository` field of its manifest".to_owned()];
+    if !others.is_empty() {
+        tried.push(format!(
+            "the manifests of versions {}",
+            list_versions(others)
+        ));
+    }
+    if id.source_id().is_registry() && !workspace.config().offline() {
+        tried.push(format!(
+            "the registry {}",
+            id.source_id().display_registry_name()
+        ));
+    }
+    if let Some((other, repository)) = other_version_repository(workspace.config(), others) {
+        if verbosity >= Verbosity::Normal {
+            println!(
+                "{dependency} {} has no repository, using the one of version {} at {repository}",
+                id.version(),
+                other.version()
+            );
+        }
+        return Ok(repository);
+    }
+    if let Some(repository) = registry_repository(workspace.config(), id) {
+        if verbosity >= Verbosity::Normal {
+            println!(
+                "{dependency} has no repository in its manifest, using {repository} from the registry {}",
+                id.source_id().display_registry_name()
+            );
+        }
+        return Ok(repository);
+    }
+    // A documentation page is no place to clone from
+    match metadata.homepage.as_ref().filter(|url| !is_docs_page(url)) {
+        Some(homepage) => {
+            if verbosity >= Verbosity::Normal {
+                println!("{dependency} has no repository, using its homepage {homepage}");
+            }
+            Ok(homepage.clone())
+        }
+        None => {
+            tried.push(match &metadata.homepage {
+                Some(_) => "the `homepage` field, which is a documentation page".to_owned(),
+                None => "the `homepage` field".to_owned(),
+            });
+            prompt_repository(&dependency, &tried.join(", "))
+        }
+    }
+}
+
+/// The first of `ids`, newest first, whose manifest has a `repository`.
+fn other_version_repository(config: &Config, ids: &[PackageId]) -> Option<(PackageId, String)> {
+    if ids.is_empty() {
+        return None;
+    }
+    let mut ids = ids.to_vec();
+    ids.sort_by(|a, b| b.version().cmp(a.version()));
+    // Versions that can't be downloaded just don't help
+    let pkg_set = package_set(config, &ids).ok()?;
+    ids.into_iter().find_map(|id| {
+        let package = pkg_set.get_one(id).ok()?;
+        let repository = package.manifest().metadata().repository.clone()?;
+        Some((id, repository))
+    })
+}
+
+/// The `repository` the web API of the registry of `id` lists for it, which crates.io takes from
+/// the latest version of the crate and so can be there when the version in use lacks it.
+///
+/// The index has no such field, so only registries with an API can tell.
+fn registry_repository(config: &Config, id: PackageId) -> Option<String> {
+    let source_id = id.source_id();
+    if !source_id.is_registry() || config.offline() {
+        return None;
+    }
+    let api = if source_id.is_default_registry() {
+        "https://crates.io".to_owned()
+    } else {
+        registry_api(config, source_id)?
+    };
+    let mut body = Vec::new();
+    let mut handle = config.http().ok()?.borrow_mut();
+    handle.get(true).ok()?;
+    handle
+        .url(&format!(
+            "{}/api/v1/crates/{}",
+            api.trim_end_matches('/'),
+            id.name()
+        ))
+        .ok()?;
+    {
+        let mut transfer = handle.transfer();
+        transfer
+            .write_function(|data| {
+                body.extend_from_slice(data);
+                Ok(data.len())
+            })
+            .ok()?;
+        transfer.perform().ok()?;
+    }
+    if handle.response_code().ok()? != 200 {
+        return None;
+    }
+    let response: serde_json::Value = serde_json::from_slice(&body).ok()?;
+    response["crate"]["repository"]
+        .as_str()
+        .filter(|url| !url.trim().is_empty())
+        .map(str::to_owned)
+}
+
+/// The url of the web API of the registry `source_id`, from the `config.json` of its index.
+fn registry_api(config: &Config, source_id: SourceId) -> Option<String> {
+    let _lock = config.acquire_package_cache_lock().ok()?;
+    let mut source = RegistrySource::remote(source_id, &HashSet::new(), config).ok()?;
+    let registry_config = loop {
+        match source.config() {
+            Poll::Pending => source.block_until_ready().ok()?,
+            Poll::Ready(registry_config) => break registry_config.ok()?,
+        }
+    };
+    registry_config?.api
+}
+
+/// Whether `url` is the page of a crate on a documentation or crate index site, which many
+/// crates give as their homepage.
+fn is_docs_page(url: &str) -> bool {
+    let url = url.trim();
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let domain = rest.split('/').next().unwrap_or(rest);
+    let domain = domain.strip_prefix("www.").unwrap_or(domain);
+    matches!(domain, "docs.rs" | "lib.rs" | "crates.io")
+}
+
+/// Turns the `repository` of a manifest into an https url, as some crates leave out the scheme
+/// or use one that can't be cloned from anymore, like `git://`. SSH and local urls are kept.
+fn normalize_repository_url(url: &str) -> String {
+    let url = url.trim().trim_end_matches('/');
+    let url = url.strip_prefix("git+").unwrap_or(url);
+    let url = url.strip_suffix(".git").unwrap_or(url);
+    if is_ssh_url(url) {
+        return url.to_owned();
+    }
+    let url = match url.split_once("://") {
+        Some(("http" | "git", rest)) => format!("https://{rest}"),
+        Some(_) => url.to_owned(),
+        None => format!("https://{url}"),
+    };
+    // The git hosts only redirect from `www.`, which would end up in the upstream remote
+    match url.split_once("://www.") {
+        Some((scheme, rest)) if Host::from_url(&url, None).is_some() => {
+            format!("{scheme}://{rest}")
+        }
+        _ => url,
+    }
+}
+
+/// Splits a url pointing into a repository, like `https://github.com/org/repo/tree/main/crates/foo`,
+/// into the url of the repository and the directory inside it.
+fn split_repository_url(url: &str) -> (String, Option<PathBuf>) {
+    let url = url.trim_end_matches('/');
+    for marker in ["/tree/", "/blob/"] {
+        if let Some((base, rest)) = url.split_once(marker) {
+            let base = base.trim_end_matches("/-");
+            let subdir = rest
+                .split_once('/')
+                .map(|(_branch, subdir)| PathBuf::from(subdir))
+                .filter(|subdir| !subdir.as_os_str().is_empty());
+            return (base.to_owned(), subdir);
+        }
+    }
+    (url.to_owned(), None)
+}
+
+fn read_manifest(manifest_path: &Path) -> Result<toml_edit::Document> {
+    let data = fs::read_to_string(manifest_path)?;
+    Ok(data.parse().map_err(anyhow::Error::from)?)
+}
+
+/// Replaces the manifest through a rename, so that it is never left half-written.
+///
+/// The lines end with CRLF when those of the manifest being replaced do, as toml_edit writes
+/// the tables it adds with LF.
+fn write_manifest(manifest_path: &Path, manifest: &Document) -> Result<()> {
+    let crlf = fs::read_to_string(manifest_path).map_or(false, |data| data.contains("\r\n"));
+    let mut data = manifest.to_string();
+    if crlf {
+        data = data.replace("\r\n", "\n").replace('\n', "\r\n");
+    }
+    let tmp_path = manifest_path.with_file_name(".Cargo.toml.forkdep");
+    fs::write(&tmp_path, data)?;
+    if let Err(err) = fs::rename(&tmp_path, manifest_path) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+/// The git url a workspace member declares the git dependency `id` with, which is more
+/// reliable than the `repository` the crate claims.
+fn declared_git_url(workspace: &Workspace, id: PackageId) -> Option<String> {
+    workspace
+        .members()
+        .flat_map(|member| member.dependencies())
+        .find(|dep| {
+            dep.source_id().is_git()
+                && dep.package_name() == id.name()
+                && dep.source_id().canonical_url() == id.source_id().canonical_url()
+        })
+        .map(|dep| dep.source_id().url().to_string())
+}
+
+/// The directory of the crate at `crate_dir` inside the git checkout `checkout`, unless it is
+/// at the root.
+fn checkout_subdir(checkout: &Repository, crate_dir: &Path) -> Option<PathBuf> {
+    let workdir = checkout.workdir()?.canonicalize().ok()?;
+    pathdiff::diff_paths(crate_dir.canonicalize().ok()?, workdir)
+        .filter(|subdir| !subdir.as_os_str().is_empty())
+}
+
+/// The package a workspace member depends on under the name `key`, when it is renamed with
+/// `package = "..."`.
+fn renamed_package(workspace: &Workspace, key: &str) -> Option<&'static str> {
+    workspace
+        .members()
+        .flat_map(|member| member.dependencies())
+        .find(|dep| dep.name_in_toml() == key && dep.package_name() != key)
+        .map(|dep| dep.package_name().as_str())
+}
+
+/// The kinds of the dependencies of the workspace members that pull in `id`, directly or
+/// through the dependencies of other packages.
+fn dependency_kinds(
+    workspace: &Workspace,
+    lockfile: &Resolve,
+    id: PackageId,
+) -> Vec<DependencyKind> {
+    let mut kinds = Vec::new();
+    for member in workspace.members() {
+        for (dep_id, _) in lockfile.deps(member.package_id()) {
+            let kinds_of_dep = member
+                .dependencies()
+                .iter()
+                .filter(|dep| dep.matches_id(dep_id))
+                .map(|dep| DependencyKind::from_cargo(dep.kind()));
+            for kind in kinds_of_dep {
+                if !kinds.contains(&kind) && depends_on(lockfile, dep_id, id) {
+                    kinds.push(kind);
+                }
+            }
+        }
+    }
+    kinds.sort();
+    kinds
+}
+
+/// Whether `id` is `from` or one of its dependencies in the lockfile.
+fn depends_on(lockfile: &Resolve, from: PackageId, id: PackageId) -> bool {
+    let mut found = HashSet::from([from]);
+    let mut queue = VecDeque::from([from]);
+    while let Some(next) = queue.pop_front() {
+        if next == id {
+            return true;
+        }
+        for (dep, _) in lockfile.deps(next) {
+            if found.insert(dep) {
+                queue.push_back(dep);
+            }
+        }
+    }
+    false
+}
+
+/// The source of the registry `name`, configured in `.cargo/config.toml` unless it is crates.io.
+fn registry_source(config: &Config, name: &str) -> Result<SourceId> {
+    Ok(if name == "crates-io" {
+        SourceId::crates_io(config)?
+    } else {
+        SourceId::alt_registry(config, name)?
+    })
+}
+
+/// The lockfile of the workspace, generating it when there is none yet.
+fn load_lockfile(workspace: &Workspace) -> Result<Resolve> {
+    Ok(match load_pkg_lockfile(workspace)? {
+        Some(lockfile) => lockfile,
+        None if workspace.config().offline() => {
+            return Err(anyhow!(
+                "no Cargo.lock found in {}, it can't be generated offline",
+                workspace.root().display()
+            )
+            .into())
+        }
+        None => {
+            generate_lockfile(workspace)?;
+            load_pkg_lockfile(workspace)?.ok_or_else(|| anyhow!("Failed to generate lockfile"))?
+        }
+    })
+}
+
+/// A package set able to download the packages `ids` from their sources.
+fn package_set<'cfg>(config: &'cfg Config, ids: &[PackageId]) -> Result<PackageSet<'cfg>> {
+    let mut sources = SourceMap::new();
+    let source_ids: HashSet<SourceId> = ids.iter().map(|id| id.source_id()).collect();
+    for source_id in source_ids {
+        let mut source = source_id.load(config, &HashSet::new())?;
+        {
+            let _lock = config.acquire_package_cache_lock()?;
+            source.block_until_ready()?;
+        }
+        sources.insert(source);
+    }
+    Ok(PackageSet::new(ids, sources, config)?)
+}
+
+/// Finds `dependency` in the lockfile of `workspace`, together with the repository it was
+/// published from.
+pub fn resolve_repository(
+    workspace: &Workspace,
+    dependency: &str,
+    version: Option<&VersionReq>,
+    kind: Option<DependencyKind>,
+    registry: Option<&str>,
+    source: Option<&str>,
+    verbosity: Verbosity,
+) -> Result<ResolvedDependency> {
+    let mut lockfile = load_lockfile(workspace)?;
+    let members: HashSet<PackageId> = workspace.members().map(|m| m.package_id()).collect();
+    let dependency = match renamed_package(workspace, dependency) {
+        Some(package) => {
+            if verbosity >= Verbosity::Normal {
+                println!("{dependency} is a rename of the package {package}, forking {package}");
+            }
+            package
+        }
+        None => dependency,
+    };
+    // Declared since the lockfile was written, like an optional dependency about to be enabled
+    let declared = workspace
+        .members()
+        .flat_map(|member| member.dependencies())
+        .any(|dep| dep.package_name() == dependency);
+    if declared && !lockfile.iter().any(|id| id.name() == dependency) {
+        if verbosity >= Verbosity::Normal {
+            println!("{dependency} is missing from Cargo.lock, resolving it");
+        }
+        // Without writing the lockfile, that is left to the check after patching
+        lockfile = resolve_with_previous(
+            &mut PackageRegistry::new(workspace.config())?,
+            workspace,
+            &CliFeatures::new_all(true),
+            HasDevUnits::Yes,
+            Some(&lockfile),
+            None,
+            &[],
+            true,
+        )?;
+    }
+    let mut candidates: Vec<PackageId> = lockfile
+        .iter()
+        .filter(|id| id.name().as_str() == dependency && !members.contains(id))
+        .collect();
+    if let Some(kind) = kind {
+        let of_kind: Vec<PackageId> = candidates
+            .iter()
+            .copied()
+            .filter(|id| dependency_kinds(workspace, &lockfile, *id).contains(&kind))
+            .collect();
+        if of_kind.is_empty() && !candidates.is_empty() {
+            return Err(ForkdepError::WrongKind {
+                dependency: dependency.to_owned(),
+                kind,
+            });
+        }
+        candidates = of_kind;
+    }
+    if let Some(registry) = registry {
+        let source = registry_source(workspace.config(), registry)?;
+        let from_registry: Vec<PackageId> = candidates
+            .iter()
+            .copied()
+            .filter(|id| id.source_id().canonical_url() == source.canonical_url())
+            .collect();
+        if from_registry.is_empty() && !candidates.is_empty() {
+            return Err(ForkdepError::NotInRegistry {
+                dependency: dependency.to_owned(),
+                registry: registry.to_owned(),
+            });
+        }
+        candidates = from_registry;
+    }
+    if let Some(source) = source {
+        let from_source: Vec<PackageId> = candidates
+            .iter()
+            .copied()
+            .filter(|id| table_matches_source(workspace.config(), source, id.source_id()))
+            .collect();
+        if from_source.is_empty() && !candidates.is_empty() {
+            return Err(ForkdepError::NotFromSource {
+                dependency: dependency.to_owned(),
+                source_name: source.to_owned(),
+            });
+        }
+        candidates = from_source;
+    }
+    let matching: Vec<PackageId> = candidates
+        .iter()
+        .copied()
+        .filter(|id| version.map_or(true, |req| req.matches(id.version())))
+        .collect();
+    let dep_id = match (matching.as_slice(), version) {
+        ([dep_id], _) => *dep_id,
+        ([], None) => {
+            if let Some(member) = workspace
+                .members()
+                .find(|m| m.name().as_str() == dependency)
+            {
+                return Err(ForkdepError::WorkspaceMember {
+                    dependency: dependency.to_owned(),
+                    path: member.root().to_owned(),
+                });
+            }
+            return Err(dependency_not_found(workspace, &lockfile, dependency));
+        }
+        ([], Some(req)) => {
+            return Err(ForkdepError::NoMatchingVersion {
+                dependency: dependency.to_owned(),
+                req: req.clone(),
+                available: list_versions(&candidates),
+            })
+        }
+        (_, _) => {
+            let sources: HashSet<SourceId> = matching.iter().map(|id| id.source_id()).collect();
+            if sources.len() == 1 {
+                return Err(ForkdepError::MultipleVersions {
+                    dependency: dependency.to_owned(),
+                    versions: list_versions(&matching),
+                });
+            }
+            choose_source(dependency, &matching)?
+        }
+    };
+    if let Some(path) = dep_id.source_id().local_path() {
+        return Err(ForkdepError::AlreadyLocal {
+            dependency: dependency.to_owned(),
+            path,
+        });
+    }
+    let pkg_set = package_set(workspace.config(), &[dep_id])?;
+    let package = pkg_set.get_one(dep_id)?;
+    let (repository, subdir) = match declared_git_url(workspace, dep_id) {
+        Some(url) => {
+            if verbosity == Verbosity::Verbose {
+                println!("using the git url {url} of {dependency} from the manifest");
+            }
+            let subdir = Repository::discover(package.root())
+                .ok()
+                .and_then(|checkout| checkout_subdir(&checkout, package.root()));
+            (normalize_repository_url(&url), subdir)
+        }
+        None => {
+            let others: Vec<PackageId> = candidates
+                .iter()
+                .copied()
+                .filter(|id| *id != dep_id)
+                .collect();
+            let repo = published_repository(workspace, package.manifest(), &others, verbosity)?;
+            split_repository_url(&normalize_repository_url(&repo))
+        }
+    };
+    let kinds = dependency_kinds(workspace, &lockfile, dep_id);
+    let kinds_description = kinds
+        .iter()
+        .map(|kind| kind.description())
+        .collect::<Vec<_>>()
+        .join(" and ");
+    if verbosity == Verbosity::Verbose {
+        println!(
+            "resolved {} {} from {} with repository {}, used as a {}",
+            dependency,
+            dep_id.version(),
+            dep_id.source_id(),
+            repository,
+            kinds_description
+        );
+    } else if verbosity == Verbosity::Normal
+        && !kinds.is_empty()
+        && !kinds.contains(&DependencyKind::Normal)
+    {
+        println!("{dependency} is only used as a {kinds_description} of the workspace");
+    }
+    let source_id = dep_id.source_id();
+    Ok(ResolvedDependency {
+        name: dep_id.name().to_string(),
+        id: Some(dep_id),
+        repository,
+        subdir,
+        locked_rev: source_id
+            .precise()
+            .filter(|_| source_id.is_git())
+            .map(str::to_owned),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_adds_missing_scheme() {
+        assert_eq!(
+            normalize_repository_url("github.com/foo/bar"),
+            "https://github.com/foo/bar"
+        );
+    }
+
+    #[test]
+    fn normalize_upgrades_http_and_git_schemes() {
+        for url in ["http://github.com/foo/bar", "git://github.com/foo/bar"] {
+            assert_eq!(normalize_repository_url(url), "https://github.com/foo/bar");
+        }
+        assert_eq!(
+            normalize_repository_url("git+https://github.com/foo/bar"),
+            "https://github.com/foo/bar"
+        );
+    }
+
+    #[test]
+    fn normalize_keeps_ssh_and_local_urls() {
+        assert_eq!(
+            normalize_repository_url("ssh://git@github.com/foo/bar"),
+            "ssh://git@github.com/foo/bar"
+        );
+        assert_eq!(
+            normalize_repository_url("file:///srv/git/bar"),
+            "file:///srv/git/bar"
+        );
+    }
+
+    #[test]
+    fn normalize_drops_www_git_suffix_and_trailing_slash() {
+        for url in [
+            "https://www.github.com/foo/bar",
+            "https://github.com/foo/bar.git",
+            "https://github.com/foo/bar/",
+            "https://www.github.com/foo/bar.git/",
+        ] {
+            assert_eq!(normalize_repository_url(url), "https://github.com/foo/bar");
+        }
+        assert_eq!(
+            normalize_repository_url("https://www.example.com/bar"),
+            "https://www.example.com/bar"
+        );
+    }
+
+    #[test]
+    fn fork_url_ends_in_git_once() {
+        let host = Host::GitHub("github.com".to_owned());
+        for url in [
+            "https://www.github.com/foo/bar",
+            "https://github.com/foo/bar.git",
+            "https://github.com/foo/bar/",
+            "https://github.com/foo/bar.git/",
+        ] {
+            assert_eq!(
+                fork_url(&host, url, "me", false).unwrap(),
+                "https://github.com/me/bar.git"
+            );
+        }
+    }
+
+    #[test]
+    fn fork_url_of_ssh_url() {
+        let url = "git@github.com:foo/bar.git";
+        assert_eq!(normalize_repository_url(url), "git@github.com:foo/bar");
+        let host = Host::from_url(url, None).unwrap();
+        assert_eq!(
+            fork_url(&host, url, "me", true).unwrap(),
+            "git@github.com:me/bar.git"
+        );
+        assert_eq!(
+            fork_url(&host, url, "me", false).unwrap(),
+            "https://github.com/me/bar.git"
+        );
+    }
+
+    #[test]
+    fn split_finds_subdir_of_tree_and_blob_urls() {
+        assert_eq!(
+            split_repository_url("https://github.com/foo/bar/tree/main/crates/baz"),
+            (
+                "https://github.com/foo/bar".to_owned(),
+                Some(PathBuf::from("crates/baz"))
+            )
+        );
+        assert_eq!(
+            split_repository_url("https://github.com/foo/bar/blob/v1.0/crates/baz/"),
+            (
+                "https://github.com/foo/bar".to_owned(),
+                Some(PathBuf::from("crates/baz"))
+            )
+        );
+    }
+
+    #[test]
+    fn split_handles_gitlab_tree_urls() {
+        assert_eq!(
+            split_repository_url("https://gitlab.com/group/bar/-/tree/main/crates/baz"),
+            (
+                "https://gitlab.com/group/bar".to_owned(),
+                Some(PathBuf::from("crates/baz"))
+            )
+        );
+    }
+
+    #[test]
+    fn split_without_subdir() {
+        for url in [
+            "https://github.com/foo/bar",
+            "https://github.com/foo/bar/tree/main",
+            "https://gitlab.com/group/bar/-/tree/main/",
+        ] {
+            let (repository, subdir) = split_repository_url(url);
+            assert!(!repository.contains("/tree"), "{repository}");
+            assert_eq!(subdir, None, "{url}");
+        }
+    }
+}
@@ -0,0 +1,338 @@
+//! A workspace depending on a git repository, with a fork of that repository standing in for
+//! the one GitHub would create. Both are local repositories behind urls on [`HOST`].
+
+// Every test binary compiles this module, but none uses all of it
+#![allow(dead_code)]
+
+use cargo_forkdep::Cargo;
+use clap::Parser;
+use git2::{build::RepoBuilder, IndexAddOption, Repository, Signature};
+use std::{
+    env, fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{self, Output, Stdio},
+    sync::Once,
+};
+use tempfile::TempDir;
+
+/// The GitHub server of the repositories, `https://<HOST>/upstream/` and
+/// `https://<HOST>/<OWNER>/` are redirected to local directories.
+pub const HOST: &str = "github.example.com";
+
+/// The owner of the forks.
+pub const OWNER: &str = "me";
+
+/// A registry configured for all tests, which none of the dependencies come from.
+pub const REGISTRY: &str = "private";
+
+pub struct Fixture {
+    dir: TempDir,
+    dep: String,
+    repository: String,
+}
+
+impl Fixture {
+    /// Sets up a workspace depending on the git repository of `dep`, which must be unique
+    /// among the tests as the forks of all tests share a directory.
+    pub fn new(dep: &str) -> Fixture {
+        Fixture::with_repository(dep, &format!("https://{HOST}/upstream/{dep}"))
+    }
+
+    /// Like [`Fixture::new`], with the repository at `repository`, which must be on [`HOST`]
+    /// under `upstream`.
+    pub fn with_repository(dep: &str, repository: &str) -> Fixture {
+        Fixture::setup(dep, repository, repository, dep, "dependencies", None)
+    }
+
+    /// Like [`Fixture::new`], with `published` as the `repository` field of the dependency.
+    pub fn published_at(dep: &str, published: &str) -> Fixture {
+        let repository = format!("https://{HOST}/upstream/{dep}");
+        Fixture::setup(dep, &repository, published, dep, "dependencies", None)
+    }
+
+    /// Like [`Fixture::new`], with the dependency renamed to `key` in the workspace.
+    pub fn renamed(dep: &str, key: &str) -> Fixture {
+        let repository = format!("https://{HOST}/upstream/{dep}");
+        Fixture::setup(dep, &repository, &repository, key, "dependencies", None)
+    }
+
+    /// Like [`Fixture::new`], with the dependency only used on the platforms matching `cfg`.
+    pub fn for_target(dep: &str, cfg: &str) -> Fixture {
+        let repository = format!("https://{HOST}/upstream/{dep}");
+        let section = format!("target.'{cfg}'.dependencies");
+        Fixture::setup(dep, &repository, &repository, dep, &section, None)
+    }
+
+    /// Like [`Fixture::new`], with the repository a workspace that also holds the crate
+    /// `sibling` in a directory of that name, which the workspace depends on as well.
+    pub fn with_sibling(dep: &str, sibling: &str) -> Fixture {
+        let repository = format!("https://{HOST}/upstream/{dep}");
+        Fixture::setup(
+            dep,
+            &repository,
+            &repository,
+            dep,
+            "dependencies",
+            Some(sibling),
+        )
+    }
+
+    /// Like [`Fixture::new`], with a virtual manifest at the root whose member `uses` depends on
+    /// the dependency and whose member `other` has no dependencies.
+    pub fn virtual_workspace(dep: &str) -> Fixture {
+        let fixture = Fixture::new(dep);
+        let manifest = fixture.manifest();
+        let (_, dependency) = manifest.split_once("[dependencies]\n").unwrap();
+        let root = fixture.root();
+        fs::remove_dir_all(root.join("src")).unwrap();
+        write(
+            &root.join("Cargo.toml"),
+            "[workspace]\nmembers = [\"uses\", \"other\"]\n",
+        );
+        for (member, dependencies) in [("uses", dependency), ("other", "")] {
+            write(
+                &root.join(member).join("Cargo.toml"),
+                &format!(
+                    "[package]\nname = \"{member}\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n\
+                     [dependencies]\n{dependencies}"
+                ),
+            );
+            write(&root.join(member).join("src").join("lib.rs"), "");
+        }
+        commit_all(
+            &Repository::open(&root).unwrap(),
+            "make the workspace virtual",
+        );
+        fixture
+    }
+
+    fn setup(
+        dep: &str,
+        repository: &str,
+        published: &str,
+        key: &str,
+        section: &str,
+        sibling: Option<&str>,
+    ) -> Fixture {
+        home();
+        let dir = tempfile::tempdir().unwrap();
+        let fixture = Fixture {
+            dir,
+            dep: dep.to_owned(),
+            repository: repository.to_owned(),
+        };
+
+        let upstream = fixture.upstream();
+        if upstream.exists() {
+            fs::remove_dir_all(&upstream).unwrap();
+        }
+        write(
+            &upstream.join("Cargo.toml"),
+            &format!(
+                "[package]\nname = \"{dep}\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\
+                 repository = \"{published}\"\n"
+            ),
+        );
+        write(&upstream.join("src").join("lib.rs"), "");
+        if let Some(sibling) = sibling {
+            let manifest = fs::read_to_string(upstream.join("Cargo.toml")).unwrap();
+            write(
+                &upstream.join("Cargo.toml"),
+                &format!("{manifest}\n[workspace]\nmembers = [\"{sibling}\"]\n"),
+            );
+            write(
+                &upstream.join(sibling).join("Cargo.toml"),
+                &format!(
+                    "[package]\nname = \"{sibling}\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\
+                     repository = \"{published}\"\n"
+                ),
+            );
+            write(&upstream.join(sibling).join("src").join("lib.rs"), "");
+        }
+        let repo = Repository::init(&upstream).unwrap();
+        commit_all(&repo, "initial commit");
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        repo.tag_lightweight("v0.1.0", head.as_object(), false)
+            .unwrap();
+
+        let fork = fixture.remote_fork();
+        if fork.exists() {
+            fs::remove_dir_all(&fork).unwrap();
+        }
+        RepoBuilder::new()
+            .bare(true)
+            .clone(&format!("file://{}", upstream.display()), &fork)
+            .unwrap();
+
+        let root = fixture.root();
+        let mut manifest = format!(
+            "[package]\nname = \"root\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n\
+             [{section}]\n{key} = {{ git = \"{}\", package = \"{dep}\" }}\n",
+            fixture.upstream_url()
+        );
+        if let Some(sibling) = sibling {
+            manifest += &format!("{sibling} = {{ git = \"{}\" }}\n", fixture.upstream_url());
+        }
+        write(&root.join("Cargo.toml"), &manifest);
+        write(&root.join("src").join("lib.rs"), "");
+        let repo = Repository::init(&root).unwrap();
+        commit_all(&repo, "initial commit");
+        fixture
+    }
+
+    /// The directory of the workspace.
+    pub fn root(&self) -> PathBuf {
+        self.dir.path().join("root")
+    }
+
+    /// The directory of the repository of the dependency.
+    pub fn upstream(&self) -> PathBuf {
+        let prefix = format!("https://{HOST}/upstream/");
+        let path = self.repository.strip_prefix(&prefix).unwrap();
+        home().join("upstream").join(path.trim_end_matches('/'))
+    }
+
+    /// The url the workspace depends on the dependency with.
+    pub fn upstream_url(&self) -> String {
+        self.repository.clone()
+    }
+
+    /// The bare repository standing in for the fork on GitHub.
+    pub fn remote_fork(&self) -> PathBuf {
+        home().join("forks").join(format!("{}.git", self.dep))
+    }
+
+    /// The path of the fork of the dependency in the workspace.
+    pub fn fork_path(&self) -> PathBuf {
+        self.root().join("patches").join(&self.dep)
+    }
+
+    pub fn manifest_path(&self) -> PathBuf {
+        self.root().join("Cargo.toml")
+    }
+
+    pub fn manifest(&self) -> String {
+        fs::read_to_string(self.manifest_path()).unwrap()
+    }
+
+    /// Forks with `args`, without asking for confirmation.
+    pub fn fork(&self, args: &[&str]) -> cargo_forkdep::Result<()> {
+        let mut args = args.to_vec();
+        args.push("--yes");
+        self.run(&args)
+    }
+
+    /// Runs the binary with `args` and `stdin` piped in, without asking for confirmation.
+    pub fn fork_binary(&self, args: &[&str], stdin: &str) -> Output {
+        self.run_binary(&[args, &["--yes"]].concat(), stdin)
+    }
+
+    /// Runs the binary with `args` and `stdin` piped in.
+    pub fn run_binary(&self, args: &[&str], stdin: &str) -> Output {
+        let manifest_path = self.manifest_path();
+        let mut child = process::Command::new(env!("CARGO_BIN_EXE_cargo-forkdep"))
+            .arg("forkdep")
+            .args(args)
+            .arg("--manifest-path")
+            .arg(manifest_path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(stdin.as_bytes())
+            .unwrap();
+        child.wait_with_output().unwrap()
+    }
+
+    /// Runs `cargo forkdep` with `args` on the workspace.
+    pub fn run(&self, args: &[&str]) -> cargo_forkdep::Result<()> {
+        let manifest_path = self.manifest_path();
+        let mut full_args = vec!["cargo", "forkdep"];
+        full_args.extend_from_slice(args);
+        // After the subcommand, the options before it would be taken as options for forking
+        full_args.extend_from_slice(&[
+            "--quiet",
+            "--manifest-path",
+            manifest_path.to_str().unwrap(),
+        ]);
+        let Cargo::Forkdep(forkdep) = Cargo::try_parse_from(full_args).unwrap();
+        forkdep.run()
+    }
+}
+
+/// A home directory shared by all tests, whose git config redirects the repositories on
+/// [`HOST`] to local ones.
+fn home() -> PathBuf {
+    static INIT: Once = Once::new();
+    // One per test binary, as those may run at the same time
+    let binary = env::current_exe().unwrap();
+    let binary = binary.file_stem().unwrap().to_str().unwrap();
+    let home = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("home-{binary}"));
+    INIT.call_once(|| {
+        if home.exists() {
+            fs::remove_dir_all(&home).unwrap();
+        }
+        fs::create_dir_all(home.join("forks")).unwrap();
+        fs::create_dir_all(home.join("upstream")).unwrap();
+        write(
+            &home.join(".gitconfig"),
+            &format!(
+                "[user]\n\tname = test\n\temail = test@example.com\n\
+                 [url \"file://{}/\"]\n\tinsteadOf = https://{HOST}/{OWNER}/\n\
+                 [url \"file://{}/\"]\n\tinsteadOf = https://{HOST}/upstream/\n",
+                home.join("forks").display(),
+                home.join("upstream").display()
+            ),
+        );
+        write(
+            &home.join(".cargo").join("config.toml"),
+            &format!(
+                "[registries.{REGISTRY}]\nindex = \"file://{}\"\n",
+                home.join("registry").display()
+            ),
+        );
+        // libgit2 and cargo look up their configuration once, before any test uses them
+        env::set_var("HOME", &home);
+        env::remove_var("CARGO_HOME");
+        env::remove_var("XDG_CONFIG_HOME");
+        // Don't fork through the GitHub API
+        env::remove_var("CARGO_FORKDEP_TOKEN");
+        env::remove_var("GITHUB_TOKEN");
+    });
+    home
+}
+
+fn write(path: &Path, contents: &str) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, contents).unwrap();
+}
+
+fn commit_all(repo: &Repository, message: &str) {
+    let mut index = repo.index().unwrap();
+    index
+        .add_all(["*"].iter(), IndexAddOption::DEFAULT, None)
+        .unwrap();
+    index.write().unwrap();
+    let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+    let signature = Signature::now("test", "test@example.com").unwrap();
+    let parents = match repo.head() {
+        Ok(head) => vec![head.peel_to_commit().unwrap()],
+        Err(_) => Vec::new(),
+    };
+    let parents: Vec<_> = parents.iter().collect();
+    repo.commit(
+        Some("HEAD"),
+        &signature,
+        &signature,
+        message,
+        &tree,
+        &parents,
+    )
+    .unwrap();
+}
@@ -0,0 +1,17 @@
+mod common;
+
+use common::Fixture;
+use std::fs;
+
+#[test]
+fn doctor_passes_on_fresh_workspace() {
+    let fixture = Fixture::new("doctor-pass");
+    fixture.run(&["doctor"]).unwrap();
+}
+
+#[test]
+fn doctor_fails_without_git_repository() {
+    let fixture = Fixture::new("doctor-no-git");
+    fs::remove_dir_all(fixture.root().join(".git")).unwrap();
+    fixture.run(&["doctor"]).unwrap_err();
+}
@@ -0,0 +1,815 @@
+mod common;
+
+use cargo_forkdep::Cargo;
+use clap::Parser;
+use common::{Fixture, HOST, OWNER};
+use git2::Repository;
+use std::{fs, process};
+
+#[test]
+fn fork_adds_submodule_and_patch() {
+    let fixture = Fixture::new("fork-submodule");
+    fixture.fork(&["fork-submodule", "--owner", OWNER]).unwrap();
+
+    let gitmodules = fs::read_to_string(fixture.root().join(".gitmodules")).unwrap();
+    assert!(gitmodules.contains("path = patches/fork-submodule"));
+    assert!(gitmodules.contains(&format!("url = https://{HOST}/{OWNER}/fork-submodule.git")));
+    let patch = format!(
+        "[patch.\"{0}\"]\nfork-submodule = {{ path = \"patches/fork-submodule\" }} \
+         # forkdep: forked from {0}\n",
+        fixture.upstream_url()
+    );
+    assert!(
+        fixture.manifest().contains(&patch),
+        "{}",
+        fixture.manifest()
+    );
+    let fork = Repository::open(fixture.fork_path()).unwrap();
+    let tag = fork.revparse_single("refs/tags/v0.1.0").unwrap();
+    assert_eq!(
+        fork.head().unwrap().peel_to_commit().unwrap().id(),
+        tag.peel_to_commit().unwrap().id()
+    );
+}
+
+#[test]
+fn dry_run_changes_nothing() {
+    let fixture = Fixture::new("fork-dry-run");
+    let manifest = fixture.manifest();
+    fixture
+        .fork(&["fork-dry-run", "--owner", OWNER, "--dry-run"])
+        .unwrap();
+
+    assert_eq!(fixture.manifest(), manifest);
+    assert!(!fixture.root().join(".gitmodules").exists());
+    assert!(!fixture.fork_path().exists());
+}
+
+#[test]
+fn spec_selects_version_of_dependency() {
+    let fixture = Fixture::new("fork-spec");
+    let manifest = fixture.manifest();
+    assert!(fixture
+        .fork(&["fork-spec@0.2.0", "--owner", OWNER])
+        .is_err());
+    assert_eq!(fixture.manifest(), manifest);
+
+    fixture
+        .fork(&["fork-spec@0.1.0", "--owner", OWNER])
+        .unwrap();
+    assert!(fixture
+        .manifest()
+        .contains("fork-spec = { path = \"patches/fork-spec\" }"));
+}
+
+#[test]
+fn patch_table_overrides_source_of_dependency() {
+    let fixture = Fixture::new("fork-patch-table");
+    fixture
+        .fork(&[
+            "fork-patch-table",
+            "--owner",
+            OWNER,
+            "--patch-table",
+            "crates-io",
+        ])
+        .unwrap();
+
+    let patch = "[patch.crates-io]\nfork-patch-table = { path = \"patches/fork-patch-table\" }";
+    assert!(fixture.manifest().contains(patch), "{}", fixture.manifest());
+}
+
+#[test]
+fn url_forks_repository_without_lockfile_lookup() {
+    let fixture = Fixture::new("fork-url");
+    fixture
+        .fork(&["--url", &fixture.upstream_url(), "--owner", OWNER])
+        .unwrap();
+
+    let patch = "[patch.crates-io]\nfork-url = { path = \"patches/fork-url\" }";
+    assert!(fixture.manifest().contains(patch), "{}", fixture.manifest());
+    assert!(fixture.fork_path().join("Cargo.toml").exists());
+}
+
+#[test]
+fn url_updates_existing_patch_of_other_source() {
+    let fixture = Fixture::new("fork-url-patched");
+    let table = "[patch.\"https://example.com/fork-url-patched\"]";
+    let manifest = format!(
+        "{}\n{table}\nfork-url-patched = {{ git = \"https://example.com/fork-url-patched\" }}\n",
+        fixture.manifest()
+    );
+    fs::write(fixture.manifest_path(), &manifest).unwrap();
+    fixture
+        .fork(&["--url", &fixture.upstream_url(), "--owner", OWNER])
+        .unwrap();
+
+    let patch = format!("{table}\nfork-url-patched = {{ path = \"patches/fork-url-patched\" }}");
+    assert!(
+        fixture.manifest().contains(&patch),
+        "{}",
+        fixture.manifest()
+    );
+    assert!(
+        !fixture.manifest().contains("crates-io"),
+        "{}",
+        fixture.manifest()
+    );
+}
+
+#[test]
+fn fork_patches_virtual_workspace() {
+    let fixture = Fixture::virtual_workspace("fork-virtual");
+    fixture.fork(&["fork-virtual", "--owner", OWNER]).unwrap();
+
+    assert!(
+        fixture
+            .manifest()
+            .contains("fork-virtual = { path = \"patches/fork-virtual\" }"),
+        "{}",
+        fixture.manifest()
+    );
+    assert!(fixture.fork_path().join("Cargo.toml").exists());
+}
+
+#[test]
+fn patch_path_is_relative_with_forward_slashes() {
+    let fixture = Fixture::new("fork-relative");
+    let patch_dir = fixture.root().join("vendor").join("forks");
+    fixture
+        .fork(&[
+            "fork-relative",
+            "--owner",
+            OWNER,
+            "--patch-dir",
+            patch_dir.to_str().unwrap(),
+        ])
+        .unwrap();
+
+    assert!(
+        fixture
+            .manifest()
+            .contains("fork-relative = { path = \"vendor/forks/fork-relative\" }"),
+        "{}",
+        fixture.manifest()
+    );
+    assert!(patch_dir.join("fork-relative").join("Cargo.toml").exists());
+}
+
+#[test]
+fn siblings_patches_other_crates_of_the_fork() {
+    let fixture = Fixture::with_sibling("fork-siblings", "fork-siblings-macros");
+    fixture
+        .fork(&["fork-siblings", "--owner", OWNER, "--siblings"])
+        .unwrap();
+
+    let manifest = fixture.manifest();
+    assert!(
+        manifest.contains("fork-siblings = { path = \"patches/fork-siblings\" }"),
+        "{manifest}"
+    );
+    assert!(
+        manifest.contains(
+            "fork-siblings-macros = { path = \"patches/fork-siblings/fork-siblings-macros\" }"
+        ),
+        "{manifest}"
+    );
+    assert!(!fixture
+        .root()
+        .join("patches")
+        .join("fork-siblings-macros")
+        .exists());
+}
+
+#[test]
+fn no_fork_clones_upstream_directly() {
+    let fixture = Fixture::new("fork-no-fork");
+    fixture.fork(&["fork-no-fork", "--no-fork"]).unwrap();
+
+    let gitmodules = fs::read_to_string(fixture.root().join(".gitmodules")).unwrap();
+    assert!(
+        gitmodules.contains(&format!("url = {}", fixture.upstream_url())),
+        "{gitmodules}"
+    );
+    let fork = Repository::open(fixture.fork_path()).unwrap();
+    assert!(fork.find_remote("upstream").is_err());
+    assert!(fixture.manifest().contains("# forkdep: clone of https://"));
+}
+
+#[test]
+fn owner_can_be_piped_in() {
+    let fixture = Fixture::new("fork-piped-owner");
+    let output = fixture.fork_binary(
+        &["fork-piped-owner", "--no-browser"],
+        &format!("{OWNER}\r\n"),
+    );
+    assert!(output.status.success(), "{output:?}");
+
+    let gitmodules = fs::read_to_string(fixture.root().join(".gitmodules")).unwrap();
+    assert!(
+        gitmodules.contains(&format!(
+            "url = https://{HOST}/{OWNER}/fork-piped-owner.git"
+        )),
+        "{gitmodules}"
+    );
+}
+
+#[test]
+fn fork_sums_up_what_it_did() {
+    let fixture = Fixture::new("fork-summary");
+    let output = fixture.fork_binary(&["fork-summary", "--owner", OWNER], "");
+    assert!(output.status.success(), "{output:?}");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let summary = format!(
+        "forked fork-summary (0.1.0) -> {HOST}/{OWNER}/fork-summary, \
+         submodule patches/fork-summary, patched [patch.\"{}\"]",
+        fixture.upstream_url()
+    );
+    assert!(stdout.contains(&summary), "{stdout}");
+}
+
+#[test]
+fn clone_follows_url_rewrites_of_git_config() {
+    let fixture = Fixture::new("fork-rewrite");
+    let output = fixture.fork_binary(&["fork-rewrite", "--owner", OWNER, "--verbose"], "");
+    assert!(output.status.success(), "{output:?}");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let rewritten = format!("cloned from file://{}", fixture.remote_fork().display());
+    assert!(stdout.contains(&rewritten), "{stdout}");
+    let gitmodules = fs::read_to_string(fixture.root().join(".gitmodules")).unwrap();
+    assert!(
+        gitmodules.contains(&format!("url = https://{HOST}/{OWNER}/fork-rewrite.git")),
+        "{gitmodules}"
+    );
+}
+
+#[test]
+fn unconfirmed_json_run_reports_nothing() {
+    let fixture = Fixture::new("fork-unconfirmed");
+    let manifest = fixture.manifest();
+    let output = fixture.run_binary(
+        &[
+            "fork-unconfirmed",
+            "--owner",
+            OWNER,
+            "--message-format",
+            "json",
+        ],
+        "",
+    );
+    assert!(!output.status.success(), "{output:?}");
+
+    assert!(output.stdout.is_empty(), "{output:?}");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("write these changes?"), "{stderr}");
+    assert_eq!(fixture.manifest(), manifest);
+    assert!(!fixture.fork_path().exists());
+}
+
+#[test]
+fn json_run_reports_written_fork() {
+    let fixture = Fixture::new("fork-json");
+    let output = fixture.run_binary(
+        &["fork-json", "--owner", OWNER, "--message-format", "json"],
+        "y\n",
+    );
+    assert!(output.status.success(), "{output:?}");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let record: serde_json::Value = serde_json::from_str(stdout.trim()).unwrap();
+    assert_eq!(record["reason"], "dependency-forked");
+    assert_eq!(record["path"], "patches/fork-json");
+    assert!(fixture.manifest().contains("patches/fork-json"));
+}
+
+#[test]
+fn patch_table_of_other_source_warns() {
+    let fixture = Fixture::new("fork-wrong-table");
+    let output = fixture.fork_binary(
+        &[
+            "fork-wrong-table",
+            "--owner",
+            OWNER,
+            "--patch-table",
+            "crates-io",
+        ],
+        "",
+    );
+    assert!(output.status.success(), "{output:?}");
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("won't apply"), "{stderr}");
+}
+
+#[test]
+fn verify_confirms_dependency_resolves_to_fork() {
+    let fixture = Fixture::new("fork-verify");
+    let output = fixture.fork_binary(&["fork-verify", "--owner", OWNER, "--verify"], "");
+    assert!(output.status.success(), "{output:?}");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("verified that fork-verify resolves to its fork"),
+        "{stdout}"
+    );
+    let lockfile = fs::read_to_string(fixture.root().join("Cargo.lock")).unwrap();
+    assert!(!lockfile.contains(&fixture.upstream_url()), "{lockfile}");
+}
+
+#[test]
+fn verify_warns_about_patch_without_effect() {
+    let fixture = Fixture::new("fork-verify-table");
+    let output = fixture.fork_binary(
+        &[
+            "fork-verify-table",
+            "--owner",
+            OWNER,
+            "--patch-table",
+            "crates-io",
+            "--verify",
+        ],
+        "",
+    );
+    assert!(output.status.success(), "{output:?}");
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("so its patch has no effect"), "{stderr}");
+}
+
+#[test]
+fn color_is_only_used_when_asked_for_outside_terminals() {
+    let fixture = Fixture::new("fork-color");
+    let args = ["fork-color", "--owner", OWNER, "--patch-table", "crates-io"];
+    let output = fixture.fork_binary(&args, "");
+    assert!(output.status.success(), "{output:?}");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("warning: "), "{stderr}");
+    assert!(!stderr.contains('\u{1b}'), "{stderr}");
+
+    let output = fixture.fork_binary(&[&args[..], &["--force", "--color", "always"]].concat(), "");
+    assert!(output.status.success(), "{output:?}");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("33mwarning\u{1b}[0m: "), "{stderr:?}");
+}
+
+#[test]
+fn remote_name_renames_remote_of_fork() {
+    let fixture = Fixture::new("fork-remote-name");
+    fixture
+        .fork(&[
+            "fork-remote-name",
+            "--owner",
+            OWNER,
+            "--remote-name",
+            "fork",
+        ])
+        .unwrap();
+
+    let fork = Repository::open(fixture.fork_path()).unwrap();
+    assert!(fork.find_remote("origin").is_err());
+    let url = fork
+        .config()
+        .unwrap()
+        .get_string("remote.fork.url")
+        .unwrap();
+    assert_eq!(url, format!("https://{HOST}/{OWNER}/fork-remote-name.git"));
+    fork.find_remote("upstream").unwrap();
+    fork.find_reference("refs/tags/v0.1.0").unwrap();
+}
+
+#[test]
+fn status_and_sync_find_renamed_remote() {
+    let fixture = Fixture::new("fork-remote-status");
+    fixture
+        .fork(&[
+            "fork-remote-status",
+            "--owner",
+            OWNER,
+            "--remote-name",
+            "mine",
+        ])
+        .unwrap();
+    let output = fixture.run_binary(&["status"], "");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains(&format!("https://{HOST}/{OWNER}/fork-remote-status.git")),
+        "{stdout}"
+    );
+
+    // On a branch of the same name as one of the fork, without tracking it
+    let fork = Repository::open(fixture.fork_path()).unwrap();
+    let upstream = Repository::open(fixture.upstream()).unwrap();
+    let head = upstream.head().unwrap();
+    let default = head.shorthand().unwrap();
+    let commit = fork.head().unwrap().peel_to_commit().unwrap();
+    let mut branch = fork.branch(default, &commit, true).unwrap();
+    branch.set_upstream(None).unwrap();
+    fork.set_head(&format!("refs/heads/{default}")).unwrap();
+    fixture.run(&["sync"]).unwrap();
+}
+
+#[test]
+fn submodule_name_differs_from_path() {
+    let fixture = Fixture::new("fork-submodule-name");
+    fixture
+        .fork(&[
+            "fork-submodule-name",
+            "--owner",
+            OWNER,
+            "--submodule-name",
+            "custom",
+        ])
+        .unwrap();
+
+    let gitmodules = fs::read_to_string(fixture.root().join(".gitmodules")).unwrap();
+    assert!(
+        gitmodules.contains("[submodule \"custom\"]\n\tpath = patches/fork-submodule-name"),
+        "{gitmodules}"
+    );
+    fixture.run(&["unfork", "fork-submodule-name"]).unwrap();
+    assert!(!fixture.root().join(".gitmodules").exists());
+}
+
+#[test]
+fn single_branch_fetches_only_default_branch() {
+    let fixture = Fixture::new("fork-single-branch");
+    let remote = Repository::open_bare(fixture.remote_fork()).unwrap();
+    let head = remote.head().unwrap().peel_to_commit().unwrap();
+    remote.branch("other", &head, false).unwrap();
+    fixture
+        .fork(&["fork-single-branch", "--owner", OWNER, "--single-branch"])
+        .unwrap();
+
+    let fork = Repository::open(fixture.fork_path()).unwrap();
+    let default = remote.head().unwrap().shorthand().unwrap().to_owned();
+    fork.find_reference(&format!("refs/remotes/origin/{default}"))
+        .unwrap();
+    assert!(fork.find_reference("refs/remotes/origin/other").is_err());
+}
+
+#[test]
+fn fork_outside_git_repository_clones_plainly() {
+    let fixture = Fixture::new("fork-no-git");
+    fs::remove_dir_all(fixture.root().join(".git")).unwrap();
+    fixture.fork(&["fork-no-git", "--owner", OWNER]).unwrap();
+
+    assert!(!fixture.root().join(".gitmodules").exists());
+    assert!(fixture.fork_path().join("Cargo.toml").exists());
+    assert!(fixture
+        .manifest()
+        .contains("fork-no-git = { path = \"patches/fork-no-git\" }"));
+
+    fixture.run(&["unfork", "fork-no-git"]).unwrap();
+    assert!(!fixture.fork_path().exists());
+}
+
+#[test]
+fn binary_runs_without_subcommand_name_of_cargo() {
+    let fixture = Fixture::new("fork-direct");
+    fixture.fork(&["fork-direct", "--owner", OWNER]).unwrap();
+    // As the completions for `cargo-forkdep` write the command line
+    let output = process::Command::new(env!("CARGO_BIN_EXE_cargo-forkdep"))
+        .args(["status", "--manifest-path"])
+        .arg(fixture.manifest_path())
+        .output()
+        .unwrap();
+
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(String::from_utf8_lossy(&output.stdout).contains("fork-direct"));
+}
+
+#[test]
+fn failed_check_with_yes_keeps_patches_and_reports_check() {
+    let fixture = Fixture::new("fork-check-fails");
+    fs::write(
+        fixture.root().join("src").join("lib.rs"),
+        "compile_error!(\"broken\");\n",
+    )
+    .unwrap();
+    let output = fixture.fork_binary(&["fork-check-fails", "--owner", OWNER, "--check"], "");
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(
+        stderr.contains("cargo check failed with the patches applied"),
+        "{stderr}"
+    );
+    assert!(!stderr.contains("stdin is not a terminal"), "{stderr}");
+    assert!(
+        fixture.manifest().contains("[patch."),
+        "{}",
+        fixture.manifest()
+    );
+}
+
+#[test]
+fn print_url_changes_nothing() {
+    let fixture = Fixture::new("fork-print-url");
+    let manifest = fixture.manifest();
+    fixture.run(&["fork-print-url", "--print-url"]).unwrap();
+
+    assert_eq!(fixture.manifest(), manifest);
+    assert!(!fixture.root().join(".gitmodules").exists());
+    assert!(!fixture.fork_path().exists());
+}
+
+#[test]
+fn use_path_patches_to_existing_checkout() {
+    let fixture = Fixture::new("fork-use-path");
+    let checkout = fixture.root().join("vendor").join("fork-use-path");
+    Repository::clone(fixture.upstream().to_str().unwrap(), &checkout).unwrap();
+    fixture
+        .fork(&["fork-use-path", "--use-path", checkout.to_str().unwrap()])
+        .unwrap();
+
+    let patch = format!(
+        "[patch.\"{}\"]\nfork-use-path = {{ path = \"vendor/fork-use-path\" }}",
+        fixture.upstream_url()
+    );
+    assert!(
+        fixture.manifest().contains(&patch),
+        "{}",
+        fixture.manifest()
+    );
+    assert!(!fixture.root().join(".gitmodules").exists());
+}
+
+#[test]
+fn use_path_rejects_directory_without_crate() {
+    let fixture = Fixture::new("fork-use-path-empty");
+    let manifest = fixture.manifest();
+    let src = fixture.root().join("src");
+    fixture
+        .fork(&["fork-use-path-empty", "--use-path", src.to_str().unwrap()])
+        .unwrap_err();
+
+    assert_eq!(fixture.manifest(), manifest);
+}
+
+#[test]
+fn unfork_removes_patch_and_submodule() {
+    let fixture = Fixture::new("fork-unfork");
+    let manifest = fixture.manifest();
+    fixture.fork(&["fork-unfork", "--owner", OWNER]).unwrap();
+    fixture.run(&["unfork", "fork-unfork"]).unwrap();
+
+    assert_eq!(fixture.manifest(), manifest);
+    assert!(!fixture.fork_path().exists());
+}
+
+#[test]
+fn unfork_keeps_copy_shared_with_siblings_until_the_last() {
+    let fixture = Fixture::with_sibling("fork-unfork-siblings", "fork-unfork-siblings-macros");
+    let manifest = fixture.manifest();
+    fixture
+        .fork(&["fork-unfork-siblings", "--owner", OWNER, "--siblings"])
+        .unwrap();
+
+    fixture.run(&["unfork", "fork-unfork-siblings"]).unwrap();
+    assert!(fixture.fork_path().join("Cargo.toml").exists());
+    assert!(fixture.root().join(".gitmodules").exists());
+
+    fixture
+        .run(&["unfork", "fork-unfork-siblings-macros"])
+        .unwrap();
+    assert_eq!(fixture.manifest(), manifest);
+    assert!(!fixture.fork_path().exists());
+    assert!(!fixture.root().join(".gitmodules").exists());
+}
+
+#[test]
+fn unfork_leaves_use_path_checkout_alone() {
+    let fixture = Fixture::new("fork-unfork-use-path");
+    let manifest = fixture.manifest();
+    let outside = tempfile::tempdir().unwrap();
+    let checkout = outside.path().join("mycheckout");
+    Repository::clone(fixture.upstream().to_str().unwrap(), &checkout).unwrap();
+    fs::write(checkout.join("work.txt"), "uncommitted").unwrap();
+    fixture
+        .fork(&[
+            "fork-unfork-use-path",
+            "--use-path",
+            checkout.to_str().unwrap(),
+        ])
+        .unwrap();
+    assert!(
+        fixture.manifest().contains("path = \"../"),
+        "{}",
+        fixture.manifest()
+    );
+
+    fixture.run(&["unfork", "fork-unfork-use-path"]).unwrap();
+    assert_eq!(fixture.manifest(), manifest);
+    assert!(checkout.join("work.txt").exists());
+    assert!(checkout.join(".git").exists());
+}
+
+#[test]
+fn unfork_leaves_parent_repository_alone() {
+    let fixture = Fixture::new("fork-unfork-parent");
+    // A workspace nested in the repository of the fixture, patched by hand with a crate next to it
+    let nested = fixture.root().join("nested");
+    fs::create_dir_all(nested.join("src")).unwrap();
+    fs::write(nested.join("src").join("lib.rs"), "").unwrap();
+    fs::write(
+        nested.join("Cargo.toml"),
+        "[package]\nname = \"nested\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n\
+         [patch.crates-io]\nfoo = { path = \"../crates/foo\" }\n",
+    )
+    .unwrap();
+    let foo = fixture.root().join("crates").join("foo");
+    fs::create_dir_all(&foo).unwrap();
+    fs::write(foo.join("Cargo.toml"), "").unwrap();
+    let manifest_path = nested.join("Cargo.toml");
+    let Cargo::Forkdep(forkdep) = Cargo::try_parse_from([
+        "cargo",
+        "forkdep",
+        "unfork",
+        "foo",
+        "--quiet",
+        "--manifest-path",
+        manifest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    forkdep.run().unwrap();
+
+    assert!(!fs::read_to_string(&manifest_path).unwrap().contains("foo"));
+    assert!(foo.join("Cargo.toml").exists());
+    assert!(fixture.root().join(".git").exists());
+}
+
+#[test]
+fn unfork_removes_clone_made_without_submodule() {
+    let fixture = Fixture::new("fork-unfork-clone");
+    let manifest = fixture.manifest();
+    fixture
+        .fork(&["fork-unfork-clone", "--owner", OWNER, "--no-submodule"])
+        .unwrap();
+    assert!(!fixture.root().join(".gitmodules").exists());
+
+    fixture.run(&["unfork", "fork-unfork-clone"]).unwrap();
+    assert_eq!(fixture.manifest(), manifest);
+    assert!(!fixture.fork_path().exists());
+}
+
+#[test]
+fn sync_syncs_copy_shared_with_siblings() {
+    let fixture = Fixture::with_sibling("fork-sync-siblings", "fork-sync-siblings-macros");
+    fixture
+        .fork(&["fork-sync-siblings", "--owner", OWNER, "--siblings"])
+        .unwrap();
+
+    fixture.run(&["sync"]).unwrap();
+    fixture
+        .run(&["sync", "--dependency", "fork-sync-siblings-macros"])
+        .unwrap();
+}
+
+#[test]
+fn failed_clone_leaves_clean_tree() {
+    let fixture = Fixture::new("fork-no-remote-fork");
+    let manifest = fixture.manifest();
+    // The owner has no fork to clone
+    fs::remove_dir_all(fixture.remote_fork()).unwrap();
+    assert!(fixture
+        .fork(&["fork-no-remote-fork", "--owner", OWNER])
+        .is_err());
+
+    assert_eq!(fixture.manifest(), manifest);
+    assert!(!fixture.root().join(".gitmodules").exists());
+    assert!(!fixture.fork_path().exists());
+    assert!(!fixture.root().join("patches").exists());
+}
+
+#[test]
+fn failed_checkout_leaves_no_clone_behind() {
+    let fixture = Fixture::new("fork-bad-tag");
+    let manifest = fixture.manifest();
+    assert!(fixture
+        .fork(&["fork-bad-tag", "--owner", OWNER, "--tag", "nope"])
+        .is_err());
+
+    assert_eq!(fixture.manifest(), manifest);
+    assert!(!fixture.root().join(".gitmodules").exists());
+    assert!(!fixture.fork_path().exists());
+    // Not reused by the next run
+    fixture.fork(&["fork-bad-tag", "--owner", OWNER]).unwrap();
+    let fork = Repository::open(fixture.fork_path()).unwrap();
+    assert!(fork.head_detached().unwrap());
+}
+
+#[test]
+fn atomic_removes_clone_of_failed_step() {
+    let fixture = Fixture::new("fork-bad-branch");
+    let remote = Repository::open_bare(fixture.remote_fork()).unwrap();
+    let default = remote.head().unwrap().shorthand().unwrap().to_owned();
+    assert!(fixture
+        .fork(&[
+            "fork-bad-branch",
+            "--no-fork",
+            "--atomic",
+            "--rev",
+            "HEAD",
+            "--new-branch",
+            &default,
+        ])
+        .is_err());
+
+    assert!(!fixture.root().join(".gitmodules").exists());
+    assert!(!fixture.fork_path().exists());
+}
+
+#[test]
+fn fork_keeps_unrelated_directory_without_force() {
+    let fixture = Fixture::new("fork-occupied");
+    fs::create_dir_all(fixture.fork_path()).unwrap();
+    fs::write(fixture.fork_path().join("notes.txt"), "mine").unwrap();
+    assert!(fixture.fork(&["fork-occupied", "--owner", OWNER]).is_err());
+    assert!(fixture.fork_path().join("notes.txt").exists());
+
+    fixture
+        .fork(&["fork-occupied", "--owner", OWNER, "--force"])
+        .unwrap();
+    assert!(!fixture.fork_path().join("notes.txt").exists());
+    assert!(fixture.fork_path().join("Cargo.toml").exists());
+}
+
+#[test]
+fn fork_patches_renamed_dependency_by_package_name() {
+    let fixture = Fixture::renamed("fork-renamed", "renamed");
+    fixture.fork(&["renamed", "--owner", OWNER]).unwrap();
+
+    let patch = format!(
+        "[patch.\"{}\"]\nfork-renamed = {{ path = \"patches/fork-renamed\" }}",
+        fixture.upstream_url()
+    );
+    assert!(
+        fixture.manifest().contains(&patch),
+        "{}",
+        fixture.manifest()
+    );
+    assert!(fixture.fork_path().join("Cargo.toml").exists());
+}
+
+#[test]
+fn commit_commits_manifest_and_submodule() {
+    let fixture = Fixture::new("fork-commit");
+    fixture
+        .fork(&["fork-commit", "--owner", OWNER, "--commit"])
+        .unwrap();
+
+    let repo = Repository::open(fixture.root()).unwrap();
+    let head = repo.head().unwrap().peel_to_commit().unwrap();
+    assert_eq!(head.message(), Some("forkdep: patch fork-commit -> fork"));
+    let tree = head.tree().unwrap();
+    assert!(tree.get_path(".gitmodules".as_ref()).is_ok());
+    let submodule = tree.get_path("patches/fork-commit".as_ref()).unwrap();
+    assert_eq!(submodule.kind(), Some(git2::ObjectType::Commit));
+    let statuses = repo.statuses(None).unwrap();
+    assert!(statuses
+        .iter()
+        .all(|status| status.path() == Some("Cargo.lock")));
+}
+
+#[test]
+fn fork_patches_target_specific_dependency() {
+    let fixture = Fixture::for_target("fork-target", "cfg(windows)");
+    fixture.fork(&["fork-target", "--owner", OWNER]).unwrap();
+
+    let patch = format!(
+        "[patch.\"{}\"]\nfork-target = {{ path = \"patches/fork-target\" }}",
+        fixture.upstream_url()
+    );
+    assert!(
+        fixture.manifest().contains(&patch),
+        "{}",
+        fixture.manifest()
+    );
+    assert!(fixture.fork_path().join("Cargo.toml").exists());
+}
+
+#[test]
+fn fork_stays_on_default_branch_of_fork_without_version_tag() {
+    let fixture = Fixture::new("fork-trunk");
+    let remote = Repository::open_bare(fixture.remote_fork()).unwrap();
+    let default = remote.head().unwrap().shorthand().unwrap().to_owned();
+    remote
+        .find_branch(&default, git2::BranchType::Local)
+        .unwrap()
+        .rename("trunk", false)
+        .unwrap();
+    remote.set_head("refs/heads/trunk").unwrap();
+    remote.tag_delete("v0.1.0").unwrap();
+    fixture.fork(&["fork-trunk", "--owner", OWNER]).unwrap();
+
+    let fork = Repository::open(fixture.fork_path()).unwrap();
+    let head = fork.head().unwrap();
+    assert!(head.is_branch());
+    assert_eq!(head.shorthand(), Some("trunk"));
+}
@@ -0,0 +1,109 @@
+mod common;
+
+use cargo_forkdep::apply_patch;
+use common::{Fixture, OWNER};
+use std::{fs, path::Path};
+use toml_edit::Document;
+
+#[test]
+fn fork_keeps_crlf_line_endings() {
+    let fixture = Fixture::new("manifest-crlf");
+    let manifest = fixture.manifest().replace('\n', "\r\n");
+    fs::write(fixture.manifest_path(), &manifest).unwrap();
+    fixture.fork(&["manifest-crlf", "--owner", OWNER]).unwrap();
+
+    let patched = fixture.manifest();
+    assert!(patched.starts_with(&manifest), "{patched:?}");
+    assert!(patched.contains("[patch."), "{patched:?}");
+    assert_eq!(
+        patched.matches('\n').count(),
+        patched.matches("\r\n").count()
+    );
+}
+
+#[test]
+fn fork_keeps_lf_line_endings() {
+    let fixture = Fixture::new("manifest-lf");
+    fixture.fork(&["manifest-lf", "--owner", OWNER]).unwrap();
+
+    assert!(!fixture.manifest().contains('\r'));
+}
+
+#[test]
+fn fork_patches_inherited_dependency() {
+    let fixture = Fixture::new("manifest-inherited");
+    let manifest = format!(
+        "[workspace]\n\n[workspace.dependencies]\n\
+         manifest-inherited = {{ git = \"{}\" }}\n\n\
+         [package]\nname = \"root\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n\
+         [dependencies]\nmanifest-inherited.workspace = true\n",
+        fixture.upstream_url()
+    );
+    fs::write(fixture.manifest_path(), &manifest).unwrap();
+    fixture
+        .fork(&["manifest-inherited", "--owner", OWNER])
+        .unwrap();
+
+    let patched = fixture.manifest();
+    assert!(patched.starts_with(&manifest), "{patched}");
+    assert!(
+        patched.contains("manifest-inherited = { path = \"patches/manifest-inherited\" }"),
+        "{patched}"
+    );
+}
+
+#[test]
+fn fork_patches_dependency_inherited_by_member_at_workspace_root() {
+    let fixture = Fixture::virtual_workspace("manifest-inherited-member");
+    fs::write(
+        fixture.manifest_path(),
+        format!(
+            "{}\n[workspace.dependencies]\nmanifest-inherited-member = {{ git = \"{}\" }}\n",
+            fixture.manifest(),
+            fixture.upstream_url()
+        ),
+    )
+    .unwrap();
+    let member_path = fixture.root().join("uses").join("Cargo.toml");
+    let member = fs::read_to_string(&member_path).unwrap();
+    let (package, _) = member.split_once("[dependencies]").unwrap();
+    let member = format!("{package}[dependencies]\nmanifest-inherited-member.workspace = true\n");
+    fs::write(&member_path, &member).unwrap();
+    fixture
+        .fork(&["manifest-inherited-member", "--owner", OWNER])
+        .unwrap();
+
+    assert!(
+        fixture.manifest().contains(
+            "manifest-inherited-member = { path = \"patches/manifest-inherited-member\" }"
+        ),
+        "{}",
+        fixture.manifest()
+    );
+    assert_eq!(fs::read_to_string(&member_path).unwrap(), member);
+}
+
+#[test]
+fn patch_leaves_rest_of_existing_table_byte_identical() {
+    let manifest = "[package]\nname = \"root\"  # the root\nversion = \"0.1.0\"\n\n\
+                    # Forks of our own\n[patch.crates-io]\n# kept in sync by hand\n\
+                    other   = { path = \"../other\" }   # pinned\n\n\
+                    [dependencies]\nother = \"1\"\n";
+    let mut document: Document = manifest.parse().unwrap();
+    apply_patch(
+        &mut document,
+        "crates-io",
+        Path::new("patches/forked"),
+        "forked",
+        &[],
+        false,
+        None,
+    )
+    .unwrap();
+
+    let expected = manifest.replace(
+        "# pinned\n",
+        "# pinned\nforked = { path = \"patches/forked\" }\n",
+    );
+    assert_eq!(document.to_string(), expected);
+}
@@ -0,0 +1,40 @@
+mod common;
+
+use common::{Fixture, HOST, OWNER};
+use git2::Repository;
+use std::fs;
+
+/// Forks `dep` from `repository` and checks that the fork and its upstream remote
+/// are derived from the normalized url.
+fn assert_forks_from(repository: &str, dep: &str) {
+    let fixture = Fixture::with_repository(dep, repository);
+    fixture.fork(&[dep, "--owner", OWNER]).unwrap();
+
+    let gitmodules = fs::read_to_string(fixture.root().join(".gitmodules")).unwrap();
+    assert!(
+        gitmodules.contains(&format!("url = https://{HOST}/{OWNER}/{dep}.git")),
+        "{gitmodules}"
+    );
+    let fork = Repository::open(fixture.fork_path()).unwrap();
+    // The configured url, as the remote's url has been redirected
+    let upstream = fork.config().unwrap().get_string("remote.upstream.url");
+    assert_eq!(upstream.unwrap(), format!("https://{HOST}/upstream/{dep}"));
+}
+
+#[test]
+fn git_suffix() {
+    assert_forks_from(&format!("https://{HOST}/upstream/url-git.git"), "url-git");
+}
+
+#[test]
+fn trailing_slash() {
+    assert_forks_from(&format!("https://{HOST}/upstream/url-slash/"), "url-slash");
+}
+
+#[test]
+fn git_suffix_and_trailing_slash() {
+    assert_forks_from(
+        &format!("https://{HOST}/upstream/url-git-slash.git/"),
+        "url-git-slash",
+    );
+}
@@ -0,0 +1,208 @@
+mod common;
+
+use cargo::{core::Workspace, ops::generate_lockfile, util::config::Config};
+use cargo_forkdep::{resolve_repository, ForkdepError, Verbosity};
+use common::{Fixture, HOST, REGISTRY};
+use std::fs;
+
+#[test]
+fn resolves_repository_of_git_dependency() {
+    let fixture = Fixture::new("resolve-git");
+    let config = Config::default().unwrap();
+    let workspace = Workspace::new(&fixture.manifest_path(), &config).unwrap();
+    let resolved = resolve_repository(
+        &workspace,
+        "resolve-git",
+        None,
+        None,
+        None,
+        None,
+        Verbosity::Quiet,
+    )
+    .unwrap();
+
+    assert_eq!(
+        resolved.repository,
+        format!("https://{HOST}/upstream/resolve-git")
+    );
+    assert_eq!(resolved.subdir, None);
+    assert!(resolved.locked_rev.is_some());
+}
+
+#[test]
+fn prefers_git_url_of_manifest_over_published_repository() {
+    let fixture = Fixture::published_at("resolve-published", "https://example.com/elsewhere");
+    let config = Config::default().unwrap();
+    let workspace = Workspace::new(&fixture.manifest_path(), &config).unwrap();
+    let resolved = resolve_repository(
+        &workspace,
+        "resolve-published",
+        None,
+        None,
+        None,
+        None,
+        Verbosity::Quiet,
+    )
+    .unwrap();
+
+    assert_eq!(
+        resolved.repository,
+        format!("https://{HOST}/upstream/resolve-published")
+    );
+}
+
+#[test]
+fn resolves_optional_dependency_missing_from_lockfile() {
+    let fixture = Fixture::new("resolve-optional");
+    let manifest = fixture.manifest();
+    let (without, _) = manifest.split_once("resolve-optional = ").unwrap();
+    fs::write(fixture.manifest_path(), without).unwrap();
+    let config = Config::default().unwrap();
+    generate_lockfile(&Workspace::new(&fixture.manifest_path(), &config).unwrap()).unwrap();
+    let optional = manifest.replace(
+        "package = \"resolve-optional\" }",
+        "package = \"resolve-optional\", optional = true }",
+    );
+    fs::write(fixture.manifest_path(), optional).unwrap();
+    let lockfile = fixture.root().join("Cargo.lock");
+    let locked = fs::read_to_string(&lockfile).unwrap();
+
+    let config = Config::default().unwrap();
+    let workspace = Workspace::new(&fixture.manifest_path(), &config).unwrap();
+    let resolved = resolve_repository(
+        &workspace,
+        "resolve-optional",
+        None,
+        None,
+        None,
+        None,
+        Verbosity::Quiet,
+    )
+    .unwrap();
+
+    assert_eq!(
+        resolved.repository,
+        format!("https://{HOST}/upstream/resolve-optional")
+    );
+    assert_eq!(fs::read_to_string(lockfile).unwrap(), locked);
+}
+
+#[test]
+fn registry_excludes_dependencies_from_other_sources() {
+    let fixture = Fixture::new("resolve-registry");
+    let config = Config::default().unwrap();
+    let workspace = Workspace::new(&fixture.manifest_path(), &config).unwrap();
+    let err = resolve_repository(
+        &workspace,
+        "resolve-registry",
+        None,
+        None,
+        Some(REGISTRY),
+        None,
+        Verbosity::Quiet,
+    )
+    .unwrap_err();
+
+    assert!(matches!(err, ForkdepError::NotInRegistry { .. }), "{err}");
+}
+
+#[test]
+fn source_chooses_between_sources_of_dependency() {
+    let fixture = Fixture::new("resolve-sources");
+    // The same repository under another url is another source to cargo
+    let other = format!("file://{}", fixture.upstream().display());
+    let manifest = fixture.manifest();
+    fs::write(
+        fixture.manifest_path(),
+        format!("{manifest}other = {{ git = \"{other}\", package = \"resolve-sources\" }}\n"),
+    )
+    .unwrap();
+    let config = Config::default().unwrap();
+    let workspace = Workspace::new(&fixture.manifest_path(), &config).unwrap();
+    let resolved = resolve_repository(
+        &workspace,
+        "resolve-sources",
+        None,
+        None,
+        None,
+        Some(&other),
+        Verbosity::Quiet,
+    )
+    .unwrap();
+
+    assert_eq!(resolved.repository, other);
+}
+
+#[test]
+fn not_found_in_workspace_without_dependencies_says_so() {
+    let fixture = Fixture::new("resolve-no-dependencies");
+    let manifest = fixture.manifest();
+    let (without, _) = manifest.split_once("[dependencies]").unwrap();
+    fs::write(fixture.manifest_path(), without).unwrap();
+    let config = Config::default().unwrap();
+    let workspace = Workspace::new(&fixture.manifest_path(), &config).unwrap();
+    let err = resolve_repository(
+        &workspace,
+        "resolve-no-dependencies",
+        None,
+        None,
+        None,
+        None,
+        Verbosity::Quiet,
+    )
+    .unwrap_err();
+
+    assert!(matches!(err, ForkdepError::NotFound { .. }), "{err}");
+    assert!(
+        err.to_string()
+            .ends_with("the workspace has no dependencies"),
+        "{err}"
+    );
+}
+
+#[test]
+fn resolves_dependency_of_one_member_of_virtual_workspace() {
+    let fixture = Fixture::virtual_workspace("resolve-virtual");
+    let config = Config::default().unwrap();
+    let workspace = Workspace::new(&fixture.manifest_path(), &config).unwrap();
+    let resolved = resolve_repository(
+        &workspace,
+        "resolve-virtual",
+        None,
+        None,
+        None,
+        None,
+        Verbosity::Quiet,
+    )
+    .unwrap();
+
+    assert_eq!(
+        resolved.repository,
+        format!("https://{HOST}/upstream/resolve-virtual")
+    );
+}
+
+#[test]
+fn not_found_in_virtual_workspace_names_searched_members() {
+    let fixture = Fixture::virtual_workspace("resolve-virtual-missing");
+    let config = Config::default().unwrap();
+    let workspace = Workspace::new(&fixture.manifest_path(), &config).unwrap();
+    let err = resolve_repository(
+        &workspace,
+        "nonexistent",
+        None,
+        None,
+        None,
+        None,
+        Verbosity::Quiet,
+    )
+    .unwrap_err();
+
+    match &err {
+        ForkdepError::NotFound { searched, .. } => {
+            assert!(searched.contains("uses"), "{err}");
+            assert!(searched.contains("other"), "{err}");
+        }
+        _ => panic!("{err}"),
+    }
+}